@@ -84,14 +84,14 @@ impl AvifFile<'_> {
     }
 }
 
-const BASIC_BOX_SIZE: usize = 8;
-const FULL_BOX_SIZE: usize = BASIC_BOX_SIZE + 4;
+pub(crate) const BASIC_BOX_SIZE: usize = 8;
+pub(crate) const FULL_BOX_SIZE: usize = BASIC_BOX_SIZE + 4;
 
 #[derive(Debug, Clone)]
 pub struct FtypBox {
     pub major_brand: FourCC,
     pub minor_version: u32,
-    pub compatible_brands: ArrayVec<FourCC, 2>,
+    pub compatible_brands: ArrayVec<FourCC, 8>,
 }
 
 /// File Type box (chunk)
@@ -124,6 +124,7 @@ pub struct MetaBox<'data> {
     pub pitm: PitmBox,
     pub iprp: IprpBox,
     pub iref: IrefBox,
+    pub grpl: GrplBox,
 }
 
 impl MpegBox for MetaBox<'_> {
@@ -136,6 +137,8 @@ impl MpegBox for MetaBox<'_> {
             + self.iinf.len()
             + self.iprp.len()
             + if !self.iref.is_empty() { self.iref.len() } else { 0 }
+            + if !self.grpl.is_empty() { self.grpl.len() } else { 0 }
+            + self.iloc.idat_len()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
@@ -147,14 +150,18 @@ impl MpegBox for MetaBox<'_> {
         if !self.iref.is_empty() {
             self.iref.write(&mut b)?;
         }
-        self.iprp.write(&mut b)
+        self.iprp.write(&mut b)?;
+        if !self.grpl.is_empty() {
+            self.grpl.write(&mut b)?;
+        }
+        self.iloc.write_idat(&mut b)
     }
 }
 
 /// Item Info box
 #[derive(Debug, Clone)]
 pub struct IinfBox {
-    pub items: ArrayVec<InfeBox, 3>,
+    pub items: Vec<InfeBox>,
 }
 
 impl MpegBox for IinfBox {
@@ -181,6 +188,16 @@ pub struct InfeBox {
     pub id: u16,
     pub typ: FourCC,
     pub name: &'static str,
+    /// MIME type, only for `mime` items
+    pub content_type: &'static str,
+    /// Not meant to be displayed on its own, e.g. a tile of a grid
+    pub hidden: bool,
+}
+
+impl InfeBox {
+    fn has_content_type(&self) -> bool {
+        self.typ.0 == *b"mime"
+    }
 }
 
 impl MpegBox for InfeBox {
@@ -191,20 +208,28 @@ impl MpegBox for InfeBox {
         + 2 // item_protection_index
         + 4 // type
         + self.name.len() + 1 // nul-terminated
+        + if self.has_content_type() { self.content_type.len() + 1 } else { 0 }
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
-        let mut b = w.full_box(self.len(), *b"infe", 2)?;
+        let mut b = w.full_box_with_flags(self.len(), *b"infe", 2, self.hidden.into())?;
         b.u16(self.id)?;
         b.u16(0)?;
         b.push(&self.typ.0)?;
         b.push(self.name.as_bytes())?;
-        b.u8(0)
+        b.u8(0)?;
+        if self.has_content_type() {
+            b.push(self.content_type.as_bytes())?;
+            b.u8(0)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct HdlrBox {
+    /// `pict` for images, `auxv` for auxiliary image sequence tracks
+    pub handler_type: FourCC,
 }
 
 impl MpegBox for HdlrBox {
@@ -218,7 +243,7 @@ impl MpegBox for HdlrBox {
         // and it does it the way classic MacOS used to, because Quicktime.
         let mut b = w.full_box(self.len(), *b"hdlr", 0)?;
         b.u32(0)?; // old MacOS file type handler
-        b.push(b"pict")?; // MacOS Quicktime subtype
+        b.push(&self.handler_type.0)?; // MacOS Quicktime subtype
         b.u32(0)?; // Firefox 92 wants all 0 here
         b.u32(0)?; // Reserved
         b.u32(0)?; // Reserved
@@ -257,8 +282,19 @@ pub enum IpcoProp {
     Ispe(IspeBox),
     AuxC(AuxCBox),
     Colr(ColrBox),
+    ColrIcc(ColrIccBox),
     Clli(ClliBox),
     Mdcv(MdcvBox),
+    Cclv(CclvBox),
+    Amve(AmveBox),
+    Clap(ClapBox),
+    Irot(IrotBox),
+    Imir(ImirBox),
+    Pasp(PaspBox),
+    Iscl(IsclBox),
+    A1lx(A1lxBox),
+    Lsel(LselBox),
+    A1op(A1opBox),
 }
 
 impl IpcoProp {
@@ -269,8 +305,19 @@ impl IpcoProp {
             Self::Ispe(p) => p.len(),
             Self::AuxC(p) => p.len(),
             Self::Colr(p) => p.len(),
+            Self::ColrIcc(p) => p.len(),
             Self::Clli(p) => p.len(),
             Self::Mdcv(p) => p.len(),
+            Self::Cclv(p) => p.len(),
+            Self::Amve(p) => p.len(),
+            Self::Clap(p) => p.len(),
+            Self::Irot(p) => p.len(),
+            Self::Imir(p) => p.len(),
+            Self::Pasp(p) => p.len(),
+            Self::Iscl(p) => p.len(),
+            Self::A1lx(p) => p.len(),
+            Self::Lsel(p) => p.len(),
+            Self::A1op(p) => p.len(),
         }
     }
 
@@ -281,8 +328,19 @@ impl IpcoProp {
             Self::Ispe(p) => p.write(w),
             Self::AuxC(p) => p.write(w),
             Self::Colr(p) => p.write(w),
+            Self::ColrIcc(p) => p.write(w),
             Self::Clli(p) => p.write(w),
             Self::Mdcv(p) => p.write(w),
+            Self::Cclv(p) => p.write(w),
+            Self::Amve(p) => p.write(w),
+            Self::Clap(p) => p.write(w),
+            Self::Irot(p) => p.write(w),
+            Self::Imir(p) => p.write(w),
+            Self::Pasp(p) => p.write(w),
+            Self::Iscl(p) => p.write(w),
+            Self::A1lx(p) => p.write(w),
+            Self::Lsel(p) => p.write(w),
+            Self::A1op(p) => p.write(w),
         }
     }
 }
@@ -290,17 +348,21 @@ impl IpcoProp {
 /// Item Property Container box
 #[derive(Debug, Clone)]
 pub struct IpcoBox {
-    props: ArrayVec<IpcoProp, 9>,
+    props: Vec<IpcoProp>,
 }
 
 impl IpcoBox {
     pub fn new() -> Self {
-        Self { props: ArrayVec::new() }
+        Self { props: Vec::new() }
     }
 
     #[must_use]
     pub fn push(&mut self, prop: IpcoProp) -> Option<u8> {
-        self.props.try_push(prop).ok()?;
+        // ipma uses 7-bit indices (the top bit is the essential flag)
+        if self.props.len() >= 127 {
+            return None;
+        }
+        self.props.push(prop);
         Some(self.props.len() as u8) // the spec wants them off by one
     }
 
@@ -328,20 +390,23 @@ impl MpegBox for IpcoBox {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct AuxCBox {
     pub urn: &'static str,
+    /// Type-specific information, e.g. depth representation info
+    pub subtype: Vec<u8>,
 }
 
 impl AuxCBox {
     pub fn len(&self) -> usize {
-        FULL_BOX_SIZE + self.urn.len() + 1
+        FULL_BOX_SIZE + self.urn.len() + 1 + self.subtype.len()
     }
 
     pub fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.full_box(self.len(), *b"auxC", 0)?;
         b.push(self.urn.as_bytes())?;
-        b.u8(0)
+        b.u8(0)?;
+        b.push(&self.subtype)
     }
 }
 
@@ -388,16 +453,215 @@ impl MpegBox for IspeBox {
     }
 }
 
+/// Clean aperture (crop), applied before `irot` and `imir`.
+///
+/// All values are fractions (numerator, denominator). Offsets are of the center of the crop relative to the center of the image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClapBox {
+    pub width: (u32, u32),
+    pub height: (u32, u32),
+    pub horiz_off: (i32, u32),
+    pub vert_off: (i32, u32),
+}
+
+impl ClapBox {
+    /// Crop rectangle in pixels (top-left corner and size) of an image of `image_width`×`image_height`
+    pub fn from_rect(x: u32, y: u32, width: u32, height: u32, image_width: u32, image_height: u32) -> Option<Self> {
+        if width == 0 || height == 0 || x.checked_add(width)? > image_width || y.checked_add(height)? > image_height {
+            return None;
+        }
+        // (x + width/2) - image_width/2, in halves of a pixel
+        let center_off = |x: u32, width: u32, image_width: u32| {
+            let twice = i64::from(x) * 2 + i64::from(width) - i64::from(image_width);
+            Some(if twice % 2 == 0 { (i32::try_from(twice / 2).ok()?, 1) } else { (i32::try_from(twice).ok()?, 2) })
+        };
+        Some(Self {
+            width: (width, 1),
+            height: (height, 1),
+            horiz_off: center_off(x, width, image_width)?,
+            vert_off: center_off(y, height, image_height)?,
+        })
+    }
+}
+
+impl MpegBox for ClapBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 8 * 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"clap")?;
+        b.u32(self.width.0)?;
+        b.u32(self.width.1)?;
+        b.u32(self.height.0)?;
+        b.u32(self.height.1)?;
+        b.u32(self.horiz_off.0 as u32)?;
+        b.u32(self.horiz_off.1)?;
+        b.u32(self.vert_off.0 as u32)?;
+        b.u32(self.vert_off.1)
+    }
+}
+
+/// Image rotation, applied after `clap`. `ispe` is still the size before rotation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IrotBox {
+    /// In units of 90 degrees, anti-clockwise
+    pub angle: u8,
+}
+
+impl MpegBox for IrotBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"irot")?;
+        b.u8(self.angle & 3)
+    }
+}
+
+/// Image mirroring, applied after `irot`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ImirBox {
+    /// 0 = vertical axis (left and right swapped), 1 = horizontal axis (top and bottom swapped)
+    pub axis: u8,
+}
+
+impl MpegBox for ImirBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"imir")?;
+        b.u8(self.axis & 1)
+    }
+}
+
+/// Pixel aspect ratio, for non-square pixels
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PaspBox {
+    pub h_spacing: u32,
+    pub v_spacing: u32,
+}
+
+impl MpegBox for PaspBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 + 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"pasp")?;
+        b.u32(self.h_spacing)?;
+        b.u32(self.v_spacing)
+    }
+}
+
+/// Image scaling, applied after other transformations. Sizes are fractions (numerator, denominator) of the size before scaling.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IsclBox {
+    pub target_width: (u16, u16),
+    pub target_height: (u16, u16),
+}
+
+impl MpegBox for IsclBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 * 2
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"iscl", 0)?;
+        b.u16(self.target_width.0)?;
+        b.u16(self.target_width.1)?;
+        b.u16(self.target_height.0)?;
+        b.u16(self.target_height.1)
+    }
+}
+
+/// AV1 layered image indexing. Sizes of the first 3 layers, the last layer is the rest of the data.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct A1lxBox {
+    pub layer_sizes: [u32; 3],
+}
+
+impl A1lxBox {
+    fn large_size(&self) -> bool {
+        self.layer_sizes.iter().any(|&s| s > u32::from(u16::MAX))
+    }
+}
+
+impl MpegBox for A1lxBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1 + if self.large_size() { 4 * 3 } else { 2 * 3 }
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"a1lx")?;
+        let large_size = self.large_size();
+        b.u8(u8::from(large_size))?;
+        for &size in &self.layer_sizes {
+            if large_size {
+                b.u32(size)?;
+            } else {
+                b.u16(size as u16)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Layer selector. `0xFFFF` lets decoders display any layer, e.g. progressively.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LselBox {
+    pub layer_id: u16,
+}
+
+impl MpegBox for LselBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 2
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"lsel")?;
+        b.u16(self.layer_id)
+    }
+}
+
+/// AV1 operating point selector, for bitstreams with multiple operating points
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct A1opBox {
+    pub op_index: u8,
+}
+
+impl MpegBox for A1opBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"a1op")?;
+        b.u8(self.op_index)
+    }
+}
+
 /// Property→image associations
 #[derive(Debug, Clone)]
 pub struct IpmaEntry {
     pub item_id: u16,
-    pub prop_ids: ArrayVec<u8, 7>,
+    pub prop_ids: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
 pub struct IpmaBox {
-    pub entries: ArrayVec<IpmaEntry, 2>,
+    pub entries: Vec<IpmaEntry>,
 }
 
 impl MpegBox for IpmaBox {
@@ -422,10 +686,11 @@ impl MpegBox for IpmaBox {
 }
 
 /// Item Reference box
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct IrefEntryBox {
     pub from_id: u16,
-    pub to_id: u16,
+    /// Usually one, but e.g. `dimg` refers to all tiles of a grid
+    pub to_ids: Vec<u16>,
     pub typ: FourCC,
 }
 
@@ -435,20 +700,23 @@ impl MpegBox for IrefEntryBox {
         BASIC_BOX_SIZE
             + 2 // from
             + 2 // refcount
-            + 2 // to
+            + 2 * self.to_ids.len()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.basic_box(self.len(), self.typ.0)?;
         b.u16(self.from_id)?;
-        b.u16(1)?;
-        b.u16(self.to_id)
+        b.u16(self.to_ids.len() as _)?;
+        for &to_id in &self.to_ids {
+            b.u16(to_id)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct IrefBox {
-    pub entries: ArrayVec<IrefEntryBox, 3>,
+    pub entries: Vec<IrefEntryBox>,
 }
 
 impl IrefBox {
@@ -472,6 +740,59 @@ impl MpegBox for IrefBox {
     }
 }
 
+/// Groups List box
+#[derive(Debug, Clone, Default)]
+pub struct GrplBox {
+    pub groups: Vec<EntityToGroupBox>,
+}
+
+impl GrplBox {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+impl MpegBox for GrplBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + self.groups.iter().map(|g| g.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"grpl")?;
+        for group in &self.groups {
+            group.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Entity to Group box, e.g. `altr` for alternatives in order of preference
+#[derive(Debug, Clone)]
+pub struct EntityToGroupBox {
+    pub typ: FourCC,
+    /// Unique among item IDs and other group IDs
+    pub group_id: u32,
+    pub entity_ids: Vec<u32>,
+}
+
+impl MpegBox for EntityToGroupBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 4 + 4 * self.entity_ids.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), self.typ.0, 0)?;
+        b.u32(self.group_id)?;
+        b.u32(self.entity_ids.len() as u32)?;
+        for &id in &self.entity_ids {
+            b.u32(id)?;
+        }
+        Ok(())
+    }
+}
+
 /// Auxiliary item (alpha or depth map)
 #[derive(Debug, Copy, Clone)]
 #[allow(unused)]
@@ -525,6 +846,27 @@ impl MpegBox for ColrBox {
     }
 }
 
+/// ColourInformationBox with an ICC profile. Can be used together with the `nclx` [`ColrBox`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColrIccBox {
+    /// `rICC` for restricted profiles (ISO 15076-1 matrix/TRC or monochrome), `prof` otherwise
+    pub restricted: bool,
+    pub icc_profile: Vec<u8>,
+}
+
+impl MpegBox for ColrIccBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 + self.icc_profile.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"colr")?;
+        b.push(if self.restricted { b"rICC" } else { b"prof" })?;
+        b.push(&self.icc_profile)
+    }
+}
+
 /// Content Light Level Information box (`clli`), per ISOBMFF § 12.1.5 / CEA-861.3.
 ///
 /// Signals the content light level of HDR content to the display.
@@ -589,6 +931,76 @@ impl MpegBox for MdcvBox {
     }
 }
 
+/// Content Colour Volume box (`cclv`), per ISOBMFF § 12.1.6 / ITU-T H.274.
+///
+/// Describes the color volume of the content itself. Every field is optional, but at least one must be present.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CclvBox {
+    /// Content primaries in CIE 1931 xy chromaticity, encoded as the value × 50000,
+    /// within ±5_000_000. Order: \[green, blue, red\] like in [`MdcvBox`].
+    pub primaries: Option<[(i32, i32); 3]>,
+    /// Minimum luminance of the content in cd/m² × 10_000_000.
+    pub min_luminance: Option<u32>,
+    /// Maximum luminance of the content in cd/m² × 10_000_000.
+    pub max_luminance: Option<u32>,
+    /// Average luminance of the content in cd/m² × 10_000_000.
+    pub avg_luminance: Option<u32>,
+}
+
+impl MpegBox for CclvBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1
+        + self.primaries.map_or(0, |_| 24)
+        + [self.min_luminance, self.max_luminance, self.avg_luminance].iter().flatten().count() * 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"cclv")?;
+        // cancel and persistence flags are reserved zero in the box
+        b.u8(u8::from(self.primaries.is_some()) << 5
+            | u8::from(self.min_luminance.is_some()) << 4
+            | u8::from(self.max_luminance.is_some()) << 3
+            | u8::from(self.avg_luminance.is_some()) << 2)?;
+        if let Some(primaries) = &self.primaries {
+            for &(x, y) in primaries {
+                b.u32(x as u32)?;
+                b.u32(y as u32)?;
+            }
+        }
+        for luminance in [self.min_luminance, self.max_luminance, self.avg_luminance].into_iter().flatten() {
+            b.u32(luminance)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ambient Viewing Environment box (`amve`), per ISOBMFF § 12.1.7 / ITU-T H.274.
+///
+/// Describes the viewing environment the content was graded for.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AmveBox {
+    /// Ambient illuminance in lux × 10000, non-zero. For example, 5 lux = 50000.
+    pub ambient_illuminance: u32,
+    /// Ambient light chromaticity in CIE 1931 xy × 50000, within 0..=50000.
+    /// For example, D65 (0.3127, 0.3290) encodes as (15635, 16450).
+    pub ambient_light: (u16, u16),
+}
+
+impl MpegBox for AmveBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 8
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"amve")?;
+        b.u32(self.ambient_illuminance)?;
+        b.u16(self.ambient_light.0)?;
+        b.u16(self.ambient_light.1)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Av1CBox {
     pub seq_profile: u8,
@@ -647,7 +1059,9 @@ impl MpegBox for PitmBox {
 pub struct IlocBox<'data> {
     /// update before writing
     pub absolute_offset_start: Option<NonZeroU32>,
-    pub items: ArrayVec<IlocItem<'data>, 3>,
+    pub items: Vec<IlocItem<'data>>,
+    /// Small items generated by the muxer (like a grid's layout) are stored in `idat` instead of `mdat`
+    pub idat_items: Vec<IdatItem>,
 }
 
 #[derive(Debug, Clone)]
@@ -662,16 +1076,50 @@ pub struct IlocExtent<'data> {
     pub data: &'data [u8],
 }
 
+#[derive(Debug, Clone)]
+pub struct IdatItem {
+    pub id: u16,
+    pub data: Vec<u8>,
+}
+
+impl IlocBox<'_> {
+    /// Version 1 is needed only for `construction_method`, so files without `idat` stay as they were
+    fn version(&self) -> u8 {
+        if self.idat_items.is_empty() { 0 } else { 1 }
+    }
+
+    /// Size of the `idat` box written after `iloc`'s sibling boxes, if any
+    pub(crate) fn idat_len(&self) -> usize {
+        if self.idat_items.is_empty() {
+            return 0;
+        }
+        BASIC_BOX_SIZE + self.idat_items.iter().map(|i| i.data.len()).sum::<usize>()
+    }
+
+    pub(crate) fn write_idat<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        if self.idat_items.is_empty() {
+            return Ok(());
+        }
+        let mut b = w.basic_box(self.idat_len(), *b"idat")?;
+        for item in &self.idat_items {
+            b.push(&item.data)?;
+        }
+        Ok(())
+    }
+}
+
 impl MpegBox for IlocBox<'_> {
     #[inline(always)]
-    #[allow(unused_parens)]
+    #[allow(unused_parens, clippy::identity_op)]
     fn len(&self) -> usize {
+        let construction_method_size = if self.version() > 0 { 2 } else { 0 };
         FULL_BOX_SIZE
         + 1 // offset_size, length_size
         + 1 // base_offset_size, reserved
         + 2 // num items
         + self.items.iter().map(|i| ( // for each item
             2 // id
+            + construction_method_size
             + 2 // dat ref idx
             + 0 // base_offset_size
             + 2 // extent count
@@ -680,19 +1128,30 @@ impl MpegBox for IlocBox<'_> {
                + 4 // extent_len
             )
         )).sum::<usize>()
+        + self.idat_items.len() * (
+            2 // id
+            + construction_method_size
+            + 2 // dat ref idx
+            + 2 // extent count
+            + 4 + 4 // one extent
+        )
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
-        let mut b = w.full_box(self.len(), *b"iloc", 0)?;
+        let version = self.version();
+        let mut b = w.full_box(self.len(), *b"iloc", version)?;
         b.push(&[4 << 4 | 4, 0])?; // offset and length are 4 bytes
 
-        b.u16(self.items.len() as _)?; // num items
+        b.u16((self.items.len() + self.idat_items.len()) as _)?; // num items
         let mut next_start = if let Some(ok) = self.absolute_offset_start { ok.get() } else {
             debug_assert!(false);
             !0
         };
         for item in &self.items {
             b.u16(item.id)?;
+            if version > 0 {
+                b.u16(0)?; // construction_method: file offset
+            }
             b.u16(0)?;
             b.u16(item.extents.len() as _)?; // num extents
             for ex in &item.extents {
@@ -702,6 +1161,18 @@ impl MpegBox for IlocBox<'_> {
                 b.u32(len)?;
             }
         }
+        // offsets are relative to the start of idat's payload
+        let mut next_start = 0;
+        for item in &self.idat_items {
+            let len = item.data.len() as u32;
+            b.u16(item.id)?;
+            b.u16(1)?; // construction_method: idat
+            b.u16(0)?;
+            b.u16(1)?; // num extents
+            b.u32(next_start)?;
+            b.u32(len)?;
+            next_start += len;
+        }
         Ok(())
     }
 }
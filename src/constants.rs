@@ -1,3 +1,5 @@
+use crate::hdr::{hlg_inverse_oetf, pq_eotf};
+
 /// `Bt709` works for sRGB images.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[non_exhaustive]
@@ -5,14 +7,78 @@ pub enum ColorPrimaries {
     /// Rec.709 and sRGB
     Bt709 = 1,
     Unspecified = 2,
+    /// ITU-R BT.470 System M (historical NTSC)
+    Bt470M = 4,
+    /// ITU-R BT.470 System B, G, and BT601-6 625 (PAL)
+    Bt470BG = 5,
     /// ITU-R BT601-6 525
     Bt601 = 6,
+    /// SMPTE 240 M. Same as `Bt601`.
+    Smpte240 = 7,
+    /// Generic film (color filters using Illuminant C)
+    GenericFilm = 8,
     /// ITU-R BT2020
     Bt2020 = 9,
+    /// SMPTE ST 428-1 (CIE 1931 XYZ)
+    Xyz = 10,
     /// SMPTE ST 431-2. NB: "P3" images use DisplayP3 instead.
     DciP3 = 11,
     /// SMPTE ST 432-1
     DisplayP3 = 12,
+    /// EBU Tech. 3213-E
+    Ebu3213 = 22,
+}
+
+impl ColorPrimaries {
+    /// Red, green, and blue primaries in CIE 1931 xy chromaticity. `None` if unspecified.
+    #[must_use]
+    pub fn primaries(self) -> Option<[(f64, f64); 3]> {
+        Some(match self {
+            Self::Bt709 => [(0.640, 0.330), (0.300, 0.600), (0.150, 0.060)],
+            Self::Unspecified => return None,
+            Self::Bt470M => [(0.670, 0.330), (0.210, 0.710), (0.140, 0.080)],
+            Self::Bt470BG => [(0.640, 0.330), (0.290, 0.600), (0.150, 0.060)],
+            Self::Bt601 | Self::Smpte240 => [(0.630, 0.340), (0.310, 0.595), (0.155, 0.070)],
+            Self::GenericFilm => [(0.681, 0.319), (0.243, 0.692), (0.145, 0.049)],
+            Self::Bt2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)],
+            Self::Xyz => [(1., 0.), (0., 1.), (0., 0.)],
+            Self::DciP3 | Self::DisplayP3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)],
+            Self::Ebu3213 => [(0.630, 0.340), (0.295, 0.605), (0.155, 0.077)],
+        })
+    }
+
+    /// White point in CIE 1931 xy chromaticity. `None` if unspecified.
+    #[must_use]
+    pub fn white_point(self) -> Option<(f64, f64)> {
+        const D65: (f64, f64) = (0.3127, 0.3290);
+        const ILLUMINANT_C: (f64, f64) = (0.310, 0.316);
+        Some(match self {
+            Self::Unspecified => return None,
+            Self::Bt470M | Self::GenericFilm => ILLUMINANT_C,
+            Self::Xyz => (1. / 3., 1. / 3.),
+            Self::DciP3 => (0.314, 0.351),
+            Self::Bt709 | Self::Bt470BG | Self::Bt601 | Self::Smpte240 | Self::Bt2020 | Self::DisplayP3 | Self::Ebu3213 => D65,
+        })
+    }
+
+    /// Primaries and white point in the encoding used by [`crate::Aviffy::set_mastering_display`]:
+    /// xy × 50000, with primaries in \[green, blue, red\] order.
+    #[must_use]
+    #[allow(clippy::type_complexity)]
+    pub fn mastering_display_primaries(self) -> Option<([(u16, u16); 3], (u16, u16))> {
+        let encode = |(x, y): (f64, f64)| ((x * 50000.).round() as u16, (y * 50000.).round() as u16);
+        let [red, green, blue] = self.primaries()?;
+        Some(([green, blue, red].map(encode), encode(self.white_point()?)))
+    }
+
+    /// Contribution of linear red, green, and blue to luminance (Y), derived from the primaries and white point
+    pub(crate) fn luminance_coefficients(self) -> Option<[f64; 3]> {
+        if self == Self::Xyz {
+            return Some([0., 1., 0.]);
+        }
+        let columns = self.primaries()?.map(xy_to_xyz);
+        Some(mul_vec(&invert(&transpose(columns)), &xy_to_xyz(self.white_point()?)))
+    }
 }
 
 /// This controls how color data is interpreted (gamma).
@@ -75,11 +141,262 @@ pub enum MatrixCoefficients {
     /// ITU-R BT1361
     Bt709 = 1,
     Unspecified = 2,
+    /// US FCC 73.682 (historical)
+    Fcc = 4,
+    /// ITU-R BT.470 System B, G, and BT601-6 625. Same as `Bt601`.
+    Bt470BG = 5,
     /// ITU-R BT601-6 525. This matches luma in JPEG's YCbCr when used with sRGB transfer characteristics, but is a bit off for chroma.
     Bt601 = 6,
+    /// SMPTE 240 M
+    Smpte240 = 7,
     Ycgco = 8,
     /// ITU-R BT2020 non-constant luminance system
     Bt2020Ncl = 9,
     /// ITU-R BT2020 constant luminance system
     Bt2020Cl = 10,
+    /// SMPTE ST 2085 (Y'D'zD'x)
+    Smpte2085 = 11,
+    /// Chromaticity-derived non-constant luminance system
+    ChromaticityDerivedNcl = 12,
+    /// Chromaticity-derived constant luminance system
+    ChromaticityDerivedCl = 13,
+    /// ITU-R BT.2100 ICtCp
+    Ictcp = 14,
+    /// SMPTE ST 2128 IPT-C2
+    IptC2 = 15,
+    /// YCgCo-R with even bit depth increase (lossless)
+    YcgcoRe = 16,
+    /// YCgCo-R with odd bit depth increase (lossless)
+    YcgcoRo = 17,
+}
+
+/// The number isn't a known code point of ITU-T H.273 for this type
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnknownCodePoint(pub u16);
+
+impl std::fmt::Display for UnknownCodePoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown H.273 code point {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCodePoint {}
+
+macro_rules! code_point_conversions {
+    ($name:ident: $($variant:ident),+) => {
+        #[allow(deprecated)]
+        impl TryFrom<u16> for $name {
+            type Error = UnknownCodePoint;
+
+            fn try_from(code: u16) -> Result<Self, Self::Error> {
+                $(if code == Self::$variant as u16 {
+                    return Ok(Self::$variant);
+                })+
+                Err(UnknownCodePoint(code))
+            }
+        }
+
+        impl From<$name> for u16 {
+            #[inline]
+            fn from(value: $name) -> u16 {
+                value as u16
+            }
+        }
+    };
+}
+
+code_point_conversions!(ColorPrimaries: Bt709, Unspecified, Bt470M, Bt470BG, Bt601, Smpte240, GenericFilm, Bt2020, Xyz, DciP3, DisplayP3, Ebu3213);
+code_point_conversions!(TransferCharacteristics: Bt709, Unspecified, Bt470M, Bt470BG, Bt601, Smpte240, Linear, Log, LogSqrt, Iec61966, Bt1361, Srgb, Bt2020_10, Bt2020_12, Smpte2084, Smpte428, Hlg);
+code_point_conversions!(MatrixCoefficients: Rgb, Bt709, Unspecified, Fcc, Bt470BG, Bt601, Smpte240, Ycgco, Bt2020Ncl, Bt2020Cl, Smpte2085, ChromaticityDerivedNcl, ChromaticityDerivedCl, Ictcp, IptC2, YcgcoRe, YcgcoRo);
+
+/// Finds CICP color primaries, transfer characteristics, and matrix coefficients equivalent to an ICC profile,
+/// so that a compact `nclx` can be written instead of embedding the whole profile.
+///
+/// Uses the `cicp` tag if the profile has one (ICC v4.4), otherwise recognizes RGB matrix/TRC profiles
+/// with sRGB, Display P3, or BT.2020 primaries, and sRGB, BT.709, linear, PQ (with 1.0 = 10000 cd/m²), or HLG curves.
+/// [`crate::Aviffy::set_icc_profile_or_cicp`] uses this to write the profile as CICP values when possible.
+///
+/// The last element is the full-range flag from the `cicp` tag. Matrix/TRC profiles don't describe
+/// the matrix or range, because these depend on how the image has been encoded to AV1,
+/// so for them the matrix is `MatrixCoefficients::Unspecified` and the range is `None`.
+///
+/// Returns `None` if the profile isn't equivalent to any of these (e.g. Adobe RGB or CMYK profiles).
+#[must_use]
+pub fn cicp_from_icc_profile(icc: &[u8]) -> Option<(ColorPrimaries, TransferCharacteristics, MatrixCoefficients, Option<bool>)> {
+    let icc = IccProfile::new(icc)?;
+
+    if let Some(cicp) = icc.tag(*b"cicp") {
+        if cicp.get(..4)? != b"cicp" {
+            return None;
+        }
+        let color_primaries = ColorPrimaries::try_from(u16::from(*cicp.get(8)?)).ok()?;
+        let transfer_characteristics = TransferCharacteristics::try_from(u16::from(*cicp.get(9)?)).ok()?;
+        let matrix_coefficients = MatrixCoefficients::try_from(u16::from(*cicp.get(10)?)).ok()?;
+        let full_range = *cicp.get(11)? != 0;
+        return Some((color_primaries, transfer_characteristics, matrix_coefficients, Some(full_range)));
+    }
+
+    if icc.data.get(16..20)? != b"RGB " {
+        return None;
+    }
+    // Media white point is D50 in v4 profiles, but in v2 it can be the display's white
+    let white = icc.xyz(*b"wtpt")?;
+    let d65 = ColorPrimaries::Bt709.white_point()?;
+    if !close_to(&white, &D50, 0.01) && !close_to(&white, &xy_to_xyz(d65), 0.01) {
+        return None;
+    }
+    let colorants = [icc.xyz(*b"rXYZ")?, icc.xyz(*b"gXYZ")?, icc.xyz(*b"bXYZ")?];
+    let color_primaries = [ColorPrimaries::Bt709, ColorPrimaries::DisplayP3, ColorPrimaries::Bt2020].into_iter().find(|cp| {
+        cp.primaries().zip(cp.white_point()).is_some_and(|(primaries, white)| {
+            let expected = d50_colorants(primaries, white);
+            colorants.iter().zip(&expected).all(|(c, e)| close_to(c, e, 0.003))
+        })
+    })?;
+
+    let trcs = [icc.tag(*b"rTRC")?, icc.tag(*b"gTRC")?, icc.tag(*b"bTRC")?];
+    let transfer_characteristics = [
+        (TransferCharacteristics::Srgb, srgb_eotf as fn(f64) -> f64),
+        (TransferCharacteristics::Bt709, bt709_eotf),
+        (TransferCharacteristics::Linear, |x| x),
+        (TransferCharacteristics::Smpte2084, |x| pq_eotf(x) / 10000.),
+        (TransferCharacteristics::Hlg, hlg_inverse_oetf),
+    ].into_iter().find(|&(_, eotf)| {
+        trcs.iter().all(|trc| (0..=64).all(|i| {
+            let x = f64::from(i) / 64.;
+            eval_trc(trc, x).is_some_and(|y| (y - eotf(x)).abs() < 0.002)
+        }))
+    })?.0;
+
+    // BT.2020 uses the same curve as BT.709, but has its own code point
+    let transfer_characteristics = match (color_primaries, transfer_characteristics) {
+        (ColorPrimaries::Bt2020, TransferCharacteristics::Bt709) => TransferCharacteristics::Bt2020_10,
+        (_, tc) => tc,
+    };
+    Some((color_primaries, transfer_characteristics, MatrixCoefficients::Unspecified, None))
+}
+
+const D50: [f64; 3] = [0.9642, 1., 0.8249];
+
+struct IccProfile<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> IccProfile<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        (data.get(36..40)? == b"acsp").then_some(Self { data })
+    }
+
+    fn tag(&self, signature: [u8; 4]) -> Option<&'a [u8]> {
+        let count = be_u32(self.data.get(128..)?)? as usize;
+        let table = self.data.get(132..132 + count.checked_mul(12)?)?;
+        let entry = table.chunks_exact(12).find(|e| e[..4] == signature)?;
+        let offset = be_u32(&entry[4..])? as usize;
+        let size = be_u32(&entry[8..])? as usize;
+        self.data.get(offset..offset.checked_add(size)?)
+    }
+
+    /// `XYZType` tag
+    fn xyz(&self, signature: [u8; 4]) -> Option<[f64; 3]> {
+        let tag = self.tag(signature)?;
+        if tag.get(..4)? != b"XYZ " {
+            return None;
+        }
+        Some([s15_fixed16(tag.get(8..)?)?, s15_fixed16(tag.get(12..)?)?, s15_fixed16(tag.get(16..)?)?])
+    }
+}
+
+/// `curv` or `para` tag at `x` in 0..=1
+fn eval_trc(tag: &[u8], x: f64) -> Option<f64> {
+    match tag.get(..4)? {
+        b"curv" => {
+            let count = be_u32(tag.get(8..)?)? as usize;
+            let entries = tag.get(12..12 + count.checked_mul(2)?)?;
+            let entry = |i: usize| f64::from(u16::from_be_bytes([entries[i * 2], entries[i * 2 + 1]]));
+            Some(match count {
+                0 => x,
+                1 => x.powf(entry(0) / 256.), // u8Fixed8 gamma
+                _ => {
+                    let pos = x * (count - 1) as f64;
+                    let i = (pos.floor() as usize).min(count - 2);
+                    let frac = pos - i as f64;
+                    (entry(i) * (1. - frac) + entry(i + 1) * frac) / 65535.
+                },
+            })
+        },
+        b"para" => {
+            let function_type = u16::from_be_bytes([*tag.get(8)?, *tag.get(9)?]);
+            let num_params = [1, 3, 4, 5, 7].get(usize::from(function_type)).copied()?;
+            let mut p = [0.; 7];
+            for (i, p) in p.iter_mut().enumerate().take(num_params) {
+                *p = s15_fixed16(tag.get(12 + i * 4..)?)?;
+            }
+            let [g, a, b, c, d, e, f] = p;
+            Some(match function_type {
+                0 => x.powf(g),
+                1 => if x >= -b / a { (a * x + b).powf(g) } else { 0. },
+                2 => if x >= -b / a { (a * x + b).powf(g) + c } else { c },
+                3 => if x >= d { (a * x + b).powf(g) } else { c * x },
+                _ => if x >= d { (a * x + b).powf(g) + e } else { c * x + f },
+            })
+        },
+        _ => None,
+    }
+}
+
+fn srgb_eotf(x: f64) -> f64 {
+    if x <= 0.04045 { x / 12.92 } else { ((x + 0.055) / 1.055).powf(2.4) }
+}
+
+fn bt709_eotf(x: f64) -> f64 {
+    if x < 0.081 { x / 4.5 } else { ((x + 0.099) / 1.099).powf(1. / 0.45) }
+}
+
+/// What the `rXYZ`/`gXYZ`/`bXYZ` tags should be for these primaries: the RGB→XYZ matrix, chromatically adapted to the D50 PCS with Bradford
+fn d50_colorants(primaries: [(f64, f64); 3], white: (f64, f64)) -> [[f64; 3]; 3] {
+    let columns = primaries.map(xy_to_xyz);
+    let m = transpose(columns);
+    let scale = mul_vec(&invert(&m), &xy_to_xyz(white));
+    const BRADFORD: [[f64; 3]; 3] = [[0.8951, 0.2664, -0.1614], [-0.7502, 1.7135, 0.0367], [0.0389, -0.0685, 1.0296]];
+    let src = mul_vec(&BRADFORD, &xy_to_xyz(white));
+    let dst = mul_vec(&BRADFORD, &D50);
+    let cone_scale = [[dst[0] / src[0], 0., 0.], [0., dst[1] / src[1], 0.], [0., 0., dst[2] / src[2]]];
+    let adapt = mul(&invert(&BRADFORD), &mul(&cone_scale, &BRADFORD));
+    [0, 1, 2].map(|i| mul_vec(&adapt, &columns[i].map(|c| c * scale[i])))
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1., (1. - x - y) / y]
+}
+
+fn close_to(a: &[f64; 3], b: &[f64; 3], tolerance: f64) -> bool {
+    a.iter().zip(b).all(|(a, b)| (a - b).abs() < tolerance)
+}
+
+fn transpose(m: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    [0, 1, 2].map(|r| [0, 1, 2].map(|c| m[c][r]))
+}
+
+fn mul(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    [0, 1, 2].map(|r| [0, 1, 2].map(|c| (0..3).map(|i| a[r][i] * b[i][c]).sum()))
+}
+
+fn mul_vec(m: &[[f64; 3]; 3], v: &[f64; 3]) -> [f64; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+fn invert(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2, c1, c2) = ((r + 1) % 3, (r + 2) % 3, (c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let det = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum::<f64>();
+    [0, 1, 2].map(|r| [0, 1, 2].map(|c| cofactor(c, r) / det))
+}
+
+fn be_u32(data: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(..4)?.try_into().ok()?))
+}
+
+fn s15_fixed16(data: &[u8]) -> Option<f64> {
+    Some(f64::from(be_u32(data)? as i32) / 65536.)
 }
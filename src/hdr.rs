@@ -0,0 +1,289 @@
+//! Helpers for HDR metadata
+
+use crate::boxes::{ClliBox, MdcvBox};
+use crate::constants::{ColorPrimaries, TransferCharacteristics};
+use std::io;
+
+/// Color volume of the display used to master HDR content (SMPTE ST 2086). See [`crate::Aviffy::set_mastering_display_metadata`].
+///
+/// Chromaticities are CIE 1931 xy coordinates, and luminance is in cd/m² (nits).
+/// Values are validated when created, so they can always be written to a file.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MasteringDisplay {
+    red: (f64, f64),
+    green: (f64, f64),
+    blue: (f64, f64),
+    white_point: (f64, f64),
+    max_luminance: f64,
+    min_luminance: f64,
+}
+
+impl MasteringDisplay {
+    /// Display with arbitrary primaries, e.g. `red` = `(0.68, 0.32)`, and luminance range, e.g. `1000.` to `0.0001` cd/m².
+    ///
+    /// Chromaticities must be within 0..=1, and luminance within 0..=10000 cd/m², with `max_luminance` > `min_luminance`.
+    pub fn new(red: (f64, f64), green: (f64, f64), blue: (f64, f64), white_point: (f64, f64), max_luminance: f64, min_luminance: f64) -> io::Result<Self> {
+        let valid_xy = |(x, y): (f64, f64)| (0. ..=1.).contains(&x) && (0. ..=1.).contains(&y) && x + y <= 1.;
+        if ![red, green, blue, white_point].into_iter().all(valid_xy) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "chromaticity must be within 0..=1"));
+        }
+        if !(0. ..=10000.).contains(&min_luminance) || !(0. ..=10000.).contains(&max_luminance) || max_luminance <= min_luminance {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "luminance must be within 0..=10000 cd/m², with max > min"));
+        }
+        Ok(Self { red, green, blue, white_point, max_luminance, min_luminance })
+    }
+
+    /// Display with standard primaries and white point. `ColorPrimaries::Unspecified` is an error.
+    pub fn from_color_primaries(color_primaries: ColorPrimaries, max_luminance: f64, min_luminance: f64) -> io::Result<Self> {
+        let (Some([red, green, blue]), Some(white_point)) = (color_primaries.primaries(), color_primaries.white_point()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "color primaries must be specified"));
+        };
+        Self::new(red, green, blue, white_point, max_luminance, min_luminance)
+    }
+
+    /// P3-D65 display with 1000 cd/m² peak and 0.0001 cd/m² black, like the common OLED reference monitors
+    #[must_use]
+    pub fn p3_d65_1000_nits() -> Self {
+        Self::from_color_primaries(ColorPrimaries::DisplayP3, 1000., 0.0001).unwrap()
+    }
+
+    /// BT.2020 display with 1000 cd/m² peak and 0.0001 cd/m² black
+    #[must_use]
+    pub fn bt2020_1000_nits() -> Self {
+        Self::from_color_primaries(ColorPrimaries::Bt2020, 1000., 0.0001).unwrap()
+    }
+
+    /// BT.2020 display with 4000 cd/m² peak and 0.005 cd/m² black, like the common 4000-nit LCD reference monitors
+    #[must_use]
+    pub fn bt2020_4000_nits() -> Self {
+        Self::from_color_primaries(ColorPrimaries::Bt2020, 4000., 0.005).unwrap()
+    }
+
+    /// Peak luminance in cd/m²
+    #[inline]
+    #[must_use]
+    pub fn max_luminance(&self) -> f64 {
+        self.max_luminance
+    }
+
+    /// Black level in cd/m²
+    #[inline]
+    #[must_use]
+    pub fn min_luminance(&self) -> f64 {
+        self.min_luminance
+    }
+
+    /// Rounded to the nearest representable values: chromaticity × 50000 in \[green, blue, red\] order, and luminance × 10000
+    pub(crate) fn mdcv(&self) -> MdcvBox {
+        let xy = |(x, y): (f64, f64)| ((x * 50000.).round() as u16, (y * 50000.).round() as u16);
+        MdcvBox {
+            primaries: [xy(self.green), xy(self.blue), xy(self.red)],
+            white_point: xy(self.white_point),
+            max_luminance: (self.max_luminance * 10000.).round() as u32,
+            min_luminance: (self.min_luminance * 10000.).round() as u32,
+        }
+    }
+}
+
+/// Measures MaxCLL and MaxFALL (CTA-861.3) of decoded HDR frames. See [`crate::Aviffy::set_content_light_level_metadata`].
+///
+/// The light level of a pixel is its brightest linear R, G, or B component in cd/m².
+/// MaxCLL is the brightest pixel of all frames, and MaxFALL is the highest frame-average pixel light level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentLightLevel {
+    peak_luminance: f64,
+    max_content_light_level: f64,
+    max_pic_average_light_level: f64,
+}
+
+impl Default for ContentLightLevel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContentLightLevel {
+    /// No frames analyzed yet. Assumes 1000 cd/m² peak luminance, see [`Self::set_peak_luminance`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            peak_luminance: 1000.,
+            max_content_light_level: 0.,
+            max_pic_average_light_level: 0.,
+        }
+    }
+
+    /// Luminance in cd/m² of the value 1.0 in `Linear` frames, and the nominal peak display luminance for `Hlg` frames.
+    ///
+    /// `Smpte2084` (PQ) is absolute, and isn't affected by this.
+    #[inline]
+    pub fn set_peak_luminance(&mut self, cd_m2: f64) -> &mut Self {
+        self.peak_luminance = cd_m2;
+        self
+    }
+
+    /// Analyze one frame, given as separate red, green, and blue planes of equal length, with values in 0..=1.
+    ///
+    /// `transfer` must be `Linear`, `Smpte2084` (PQ), or `Hlg`.
+    /// `primaries` are needed for HLG, which scales light by luminance. They're not used for the other transfer functions.
+    pub fn add_frame(&mut self, red: &[f32], green: &[f32], blue: &[f32], transfer: TransferCharacteristics, primaries: ColorPrimaries) -> io::Result<&mut Self> {
+        if red.is_empty() || red.len() != green.len() || red.len() != blue.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "planes must be non-empty and of equal length"));
+        }
+        let luminance_coefficients = match transfer {
+            TransferCharacteristics::Hlg => {
+                let coefficients = primaries.luminance_coefficients();
+                Some(coefficients.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "HLG needs specified color primaries"))?)
+            },
+            TransferCharacteristics::Linear | TransferCharacteristics::Smpte2084 => None,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "only Linear, PQ and HLG transfer characteristics are supported")),
+        };
+        let peak = self.peak_luminance;
+        // BT.2100 HLG OOTF system gamma for the nominal peak luminance
+        let hlg_gamma = 1.2 + 0.42 * (peak / 1000.).log10();
+
+        let mut sum = 0.;
+        for ((&r, &g), &b) in red.iter().zip(green).zip(blue) {
+            let rgb = [r, g, b].map(|v| f64::from(v).clamp(0., 1.));
+            let light = match transfer {
+                TransferCharacteristics::Linear => rgb.map(|v| v * peak),
+                TransferCharacteristics::Smpte2084 => rgb.map(pq_eotf),
+                _ => {
+                    let rgb = rgb.map(hlg_inverse_oetf);
+                    let [kr, kg, kb] = luminance_coefficients.unwrap_or_default();
+                    let y = kr * rgb[0] + kg * rgb[1] + kb * rgb[2];
+                    let gain = if y > 0. { peak * y.powf(hlg_gamma - 1.) } else { 0. };
+                    rgb.map(|v| v * gain)
+                },
+            };
+            let pixel = light[0].max(light[1]).max(light[2]);
+            self.max_content_light_level = self.max_content_light_level.max(pixel);
+            sum += pixel;
+        }
+        self.max_pic_average_light_level = self.max_pic_average_light_level.max(sum / red.len() as f64);
+        Ok(self)
+    }
+
+    /// MaxCLL in cd/m², rounded
+    #[must_use]
+    pub fn max_content_light_level(&self) -> u16 {
+        Self::to_u16(self.max_content_light_level)
+    }
+
+    /// MaxFALL in cd/m², rounded
+    #[must_use]
+    pub fn max_pic_average_light_level(&self) -> u16 {
+        Self::to_u16(self.max_pic_average_light_level)
+    }
+
+    fn to_u16(cd_m2: f64) -> u16 {
+        cd_m2.round().clamp(0., f64::from(u16::MAX)) as u16
+    }
+
+    pub(crate) fn clli(&self) -> ClliBox {
+        ClliBox {
+            max_content_light_level: self.max_content_light_level(),
+            max_pic_average_light_level: self.max_pic_average_light_level(),
+        }
+    }
+}
+
+/// SMPTE ST 2084, to cd/m²
+pub(crate) fn pq_eotf(v: f64) -> f64 {
+    const M1: f64 = 2610. / 16384.;
+    const M2: f64 = 2523. / 4096. * 128.;
+    const C1: f64 = 3424. / 4096.;
+    const C2: f64 = 2413. / 4096. * 32.;
+    const C3: f64 = 2392. / 4096. * 32.;
+    let p = v.powf(1. / M2);
+    10000. * ((p - C1).max(0.) / (C2 - C3 * p)).powf(1. / M1)
+}
+
+/// BT.2100 HLG, to scene-linear 0..=1
+pub(crate) fn hlg_inverse_oetf(v: f64) -> f64 {
+    const A: f64 = 0.17883277;
+    const B: f64 = 0.28466892;
+    const C: f64 = 0.55991073;
+    if v <= 0.5 { v * v / 3. } else { (((v - C) / A).exp() + B) / 12. }
+}
+
+/// Gain map metadata (ISO 21496-1). See [`crate::GainMap`].
+///
+/// All values are fractions as (numerator, denominator). Gain map values, offsets and headrooms are in log2 space,
+/// e.g. an alternate headroom of `(2, 1)` means the alternate image's peak is 4× brighter than SDR white.
+///
+/// Per-channel values are in red, green, blue order. When all channels are the same, only one is written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GainMapMetadata {
+    /// Gain (log2) at the gain map's 0 value
+    pub gain_map_min: [(i32, u32); 3],
+    /// Gain (log2) at the gain map's maximum value
+    pub gain_map_max: [(i32, u32); 3],
+    /// Gamma applied to the gain map values. Must be > 0.
+    pub gamma: [(u32, u32); 3],
+    /// Offset added to the base image before applying the gain
+    pub base_offset: [(i32, u32); 3],
+    /// Offset added to the alternate image after applying the gain
+    pub alternate_offset: [(i32, u32); 3],
+    /// HDR headroom (log2) of the base image. 0 for SDR.
+    pub base_hdr_headroom: (u32, u32),
+    /// HDR headroom (log2) of the alternate image, where the gain map is fully applied
+    pub alternate_hdr_headroom: (u32, u32),
+    /// Apply the gain map in the base image's color space, instead of the alternate image's
+    pub use_base_color_space: bool,
+}
+
+impl Default for GainMapMetadata {
+    /// Gain map maps 0..1 to 1×..2× gain, from SDR to 1 stop of HDR headroom
+    fn default() -> Self {
+        Self {
+            gain_map_min: [(0, 1); 3],
+            gain_map_max: [(1, 1); 3],
+            gamma: [(1, 1); 3],
+            base_offset: [(1, 64); 3],
+            alternate_offset: [(1, 64); 3],
+            base_hdr_headroom: (0, 1),
+            alternate_hdr_headroom: (1, 1),
+            use_base_color_space: true,
+        }
+    }
+}
+
+impl GainMapMetadata {
+    /// `ToneMapImage` payload of the `tmap` item
+    pub(crate) fn tone_map_image(&self) -> io::Result<Vec<u8>> {
+        let signed = [self.gain_map_min, self.gain_map_max, self.base_offset, self.alternate_offset];
+        let valid = signed.iter().flatten().all(|&(_, d)| d != 0)
+            && self.gamma.iter().all(|&(n, d)| n != 0 && d != 0)
+            && [self.base_hdr_headroom, self.alternate_hdr_headroom].iter().all(|&(_, d)| d != 0)
+            && self.gain_map_min.iter().zip(&self.gain_map_max)
+                .all(|(&(min_n, min_d), &(max_n, max_d))| i64::from(min_n) * i64::from(max_d) <= i64::from(max_n) * i64::from(min_d));
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid gain map metadata"));
+        }
+
+        let channels = if [self.gain_map_min, self.gain_map_max, self.base_offset, self.alternate_offset].iter().all(|c| c[0] == c[1] && c[0] == c[2])
+            && self.gamma[0] == self.gamma[1] && self.gamma[0] == self.gamma[2] { 1 } else { 3 };
+        let mut out = vec![0]; // version
+        out.extend_from_slice(&0_u16.to_be_bytes()); // minimum_version
+        out.extend_from_slice(&0_u16.to_be_bytes()); // writer_version
+        out.push(if channels == 3 { 0x80 } else { 0 } | if self.use_base_color_space { 0x40 } else { 0 });
+        for (n, d) in [self.base_hdr_headroom, self.alternate_hdr_headroom] {
+            out.extend_from_slice(&n.to_be_bytes());
+            out.extend_from_slice(&d.to_be_bytes());
+        }
+        for c in 0..channels {
+            for (n, d) in [self.gain_map_min[c], self.gain_map_max[c]] {
+                out.extend_from_slice(&n.to_be_bytes());
+                out.extend_from_slice(&d.to_be_bytes());
+            }
+            out.extend_from_slice(&self.gamma[c].0.to_be_bytes());
+            out.extend_from_slice(&self.gamma[c].1.to_be_bytes());
+            for (n, d) in [self.base_offset[c], self.alternate_offset[c]] {
+                out.extend_from_slice(&n.to_be_bytes());
+                out.extend_from_slice(&d.to_be_bytes());
+            }
+        }
+        Ok(out)
+    }
+}
@@ -10,22 +10,42 @@
 
 mod boxes;
 pub mod constants;
+pub mod hdr;
+mod obu;
+mod sequence;
 mod writer;
 
 use crate::boxes::*;
+use crate::sequence::*;
+use crate::writer::Writer;
 use arrayvec::ArrayVec;
 use std::io;
+use std::num::NonZeroU32;
 
 const EXIF_TIFF_OFFSET_ZERO: [u8; 4] = 0_u32.to_be_bytes();
+const ALPHA_URN: &str = "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha";
+const APPLE_GAIN_MAP_URN: &str = "urn:com:apple:photo:2020:aux:hdrgainmap";
+const DEPTH_URN: &str = "urn:mpeg:mpegB:cicp:systems:auxiliary:depth";
+/// Set on an `ipma` property index when decoders must support the property to display the item
+const ESSENTIAL_BIT: u8 = 0x80;
 
 /// Config for the serialization (allows setting advanced image properties).
 ///
 /// See [`Aviffy::new`].
 pub struct Aviffy {
     premultiplied_alpha: bool,
+    loop_count: Option<LoopCount>,
     colr: ColrBox,
+    icc: Option<ColrIccBox>,
     clli: Option<ClliBox>,
     mdcv: Option<MdcvBox>,
+    cclv: Option<CclvBox>,
+    amve: Option<AmveBox>,
+    crop: Option<[u32; 4]>,
+    irot: Option<IrotBox>,
+    imir: Option<ImirBox>,
+    pasp: Option<PaspBox>,
+    iscl: Option<IsclBox>,
     min_seq_profile: u8,
     chroma_subsampling: (bool, bool),
     monochrome: bool,
@@ -33,6 +53,644 @@ pub struct Aviffy {
     height: u32,
     bit_depth: u8,
     exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+    gain_map: Option<GainMap>,
+    auxiliary_images: Vec<AuxiliaryImage>,
+    thumbnails: Vec<Thumbnail>,
+    alternatives: Vec<Alternative>,
+    layer_sizes: Vec<u32>,
+    a1op: Option<A1opBox>,
+}
+
+/// A single frame of an animation. See [`Aviffy::write_animation`].
+#[derive(Debug, Copy, Clone)]
+pub struct AnimationFrame<'data> {
+    /// Already-encoded AV1 data of this frame (one temporal unit)
+    pub data: &'data [u8],
+    /// How long the frame is displayed for, in units of the animation's timescale
+    pub duration: u32,
+    /// Whether the frame can be decoded on its own, without any previous frames.
+    /// The first frame must be a keyframe.
+    pub is_keyframe: bool,
+}
+
+/// How many times an animation is played. See [`Aviffy::set_loop_count`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoopCount {
+    /// Loop forever
+    Infinite,
+    /// Play this many times in total. `1` plays the animation once, without looping.
+    Times(NonZeroU32),
+}
+
+/// Rotation of the image when displayed, anti-clockwise. See [`Aviffy::set_rotation`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rotation {
+    D0 = 0,
+    D90 = 1,
+    D180 = 2,
+    D270 = 3,
+}
+
+/// Axis the image is mirrored along when displayed. See [`Aviffy::set_mirror`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MirrorAxis {
+    /// Left and right swapped (Exif orientation 2)
+    Vertical = 0,
+    /// Top and bottom swapped (Exif orientation 4)
+    Horizontal = 1,
+}
+
+/// Still image for decoders that don't support animation. See [`Aviffy::write_animation_with_poster`].
+#[derive(Debug, Copy, Clone)]
+pub enum Poster<'data> {
+    /// Use the first frame of the animation (and the first alpha frame, if any).
+    /// The frame's data is stored only once, so this costs only a few hundred bytes of headers.
+    FirstFrame,
+    /// A separately-encoded still image, with the same dimensions and depth as the animation.
+    Image {
+        color_av1_data: &'data [u8],
+        alpha_av1_data: Option<&'data [u8]>,
+    },
+}
+
+/// How an image is split into tiles of a `grid` derived image. See [`Aviffy::write_grid`].
+///
+/// Tiles are in row-major order. All tiles have the same size, and the tiles in the last row and column
+/// may extend past the image's width and height (they're cropped when displayed), but they can't be entirely outside of it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GridLayout {
+    /// 1 to 256
+    pub rows: u16,
+    /// 1 to 256
+    pub columns: u16,
+    pub tile_width: u32,
+    pub tile_height: u32,
+}
+
+impl GridLayout {
+    /// Plans the fewest tiles, of as equal size as possible, for an image of the given size,
+    /// so that each tile is at most `max_tile_width`×`max_tile_height`.
+    ///
+    /// `chroma_subsampling` is the same as in [`Aviffy::set_chroma_subsampling`]. Subsampled tiles have even dimensions.
+    pub fn plan(width: u32, height: u32, max_tile_width: u32, max_tile_height: u32, chroma_subsampling: (bool, bool)) -> io::Result<Self> {
+        let (columns, tile_width) = plan_tiles(width, max_tile_width, chroma_subsampling.0)?;
+        let (rows, tile_height) = plan_tiles(height, max_tile_height, chroma_subsampling.1)?;
+        let layout = Self { rows, columns, tile_width, tile_height };
+        layout.validate(width, height)?;
+        Ok(layout)
+    }
+
+    /// Number of tiles
+    #[inline]
+    #[must_use]
+    pub fn tile_count(&self) -> usize {
+        usize::from(self.rows) * usize::from(self.columns)
+    }
+
+    fn validate(&self, width: u32, height: u32) -> io::Result<()> {
+        if !(1..=256).contains(&self.rows) || !(1..=256).contains(&self.columns) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "grid must have 1-256 rows and columns"));
+        }
+        // The spec requires the tiles to cover the image, without any tile being entirely outside of it
+        let covers = |tiles: u16, tile_size: u32, size: u32| {
+            let tiles = u64::from(tiles);
+            (tiles - 1) * u64::from(tile_size) < u64::from(size) && u64::from(size) <= tiles * u64::from(tile_size)
+        };
+        if !covers(self.columns, self.tile_width, width) || !covers(self.rows, self.tile_height, height) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "grid tiles don't match image width/height"));
+        }
+        Ok(())
+    }
+
+    /// `ImageGrid` payload of the `grid` item
+    fn image_grid(&self, width: u32, height: u32) -> Vec<u8> {
+        let large = u16::try_from(width).is_err() || u16::try_from(height).is_err();
+        let mut out = vec![
+            0, // version
+            u8::from(large), // flags: 32-bit output size
+            (self.rows - 1) as u8,
+            (self.columns - 1) as u8,
+        ];
+        if large {
+            out.extend_from_slice(&width.to_be_bytes());
+            out.extend_from_slice(&height.to_be_bytes());
+        } else {
+            out.extend_from_slice(&(width as u16).to_be_bytes());
+            out.extend_from_slice(&(height as u16).to_be_bytes());
+        }
+        out
+    }
+}
+
+/// Number of tiles and their size along one dimension
+fn plan_tiles(size: u32, max_tile_size: u32, subsampled: bool) -> io::Result<(u16, u32)> {
+    let max_tile_size = if subsampled { max_tile_size & !1 } else { max_tile_size };
+    if size == 0 || max_tile_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing width/height or tile size"));
+    }
+    let tiles = size.div_ceil(max_tile_size);
+    let tiles = u16::try_from(tiles).ok().filter(|&t| t <= 256)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "image needs more than 256 tiles per row or column"))?;
+    let tile_size = size.div_ceil(u32::from(tiles));
+    let tile_size = if subsampled { tile_size.next_multiple_of(2) } else { tile_size };
+    Ok((tiles, tile_size))
+}
+
+/// Independently-encoded images composed on a canvas, as an `iovl` derived image. See [`Aviffy::write_overlay`].
+///
+/// Layers are drawn in the order they were added, so the last one is on top.
+#[derive(Debug, Clone, Default)]
+pub struct Overlay<'data> {
+    canvas_fill_color: [u16; 4],
+    layers: Vec<OverlayLayer<'data>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct OverlayLayer<'data> {
+    av1_data: &'data [u8],
+    width: u32,
+    height: u32,
+    x: i32,
+    y: i32,
+}
+
+impl<'data> Overlay<'data> {
+    /// Starts with an empty transparent black canvas
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Color of the canvas in areas not covered by any layer, as RGBA.
+    /// Values are scaled to 16 bits, e.g. opaque white is `[0xFFFF; 4]`.
+    #[inline]
+    pub fn set_canvas_fill_color(&mut self, rgba: [u16; 4]) -> &mut Self {
+        self.canvas_fill_color = rgba;
+        self
+    }
+
+    /// Adds an already-encoded AV1 image of `width`×`height`, with its top-left corner at `x`,`y` on the canvas.
+    ///
+    /// Layers may be partially or entirely outside of the canvas. They must have the same depth and chroma subsampling as set in [`Aviffy`].
+    #[inline]
+    pub fn add_layer(&mut self, av1_data: &'data [u8], width: u32, height: u32, x: i32, y: i32) -> &mut Self {
+        self.layers.push(OverlayLayer { av1_data, width, height, x, y });
+        self
+    }
+
+    /// `ImageOverlay` payload of the `iovl` item
+    fn image_overlay(&self, width: u32, height: u32) -> Vec<u8> {
+        let large = u16::try_from(width).is_err() || u16::try_from(height).is_err()
+            || self.layers.iter().any(|l| i16::try_from(l.x).is_err() || i16::try_from(l.y).is_err());
+        let mut out = vec![
+            0, // version
+            u8::from(large), // flags: 32-bit fields
+        ];
+        for c in self.canvas_fill_color {
+            out.extend_from_slice(&c.to_be_bytes());
+        }
+        if large {
+            out.extend_from_slice(&width.to_be_bytes());
+            out.extend_from_slice(&height.to_be_bytes());
+            for l in &self.layers {
+                out.extend_from_slice(&l.x.to_be_bytes());
+                out.extend_from_slice(&l.y.to_be_bytes());
+            }
+        } else {
+            out.extend_from_slice(&(width as u16).to_be_bytes());
+            out.extend_from_slice(&(height as u16).to_be_bytes());
+            for l in &self.layers {
+                out.extend_from_slice(&(l.x as i16).to_be_bytes());
+                out.extend_from_slice(&(l.y as i16).to_be_bytes());
+            }
+        }
+        out
+    }
+}
+
+/// HDR gain map (ISO 21496-1) that turns the image into an alternate rendition, e.g. an SDR image into HDR. See [`Aviffy::set_gain_map`].
+///
+/// The gain map is a separately-encoded AV1 image, usually smaller than the base image.
+/// The alternate image is only described by its color properties, it's not encoded.
+#[derive(Debug, Clone)]
+pub struct GainMap {
+    av1_data: Vec<u8>,
+    width: u32,
+    height: u32,
+    depth_bits: u8,
+    monochrome: bool,
+    chroma_subsampling: (bool, bool),
+    metadata: hdr::GainMapMetadata,
+    alternate_colr: ColrBox,
+    alternate_clli: Option<ClliBox>,
+    alternate_depth_bits: u8,
+}
+
+impl GainMap {
+    /// Already-encoded monochrome AV1 gain map of `width`×`height`, with `depth_bits` 8, 10 or 12.
+    ///
+    /// The alternate image defaults to 10-bit BT.2020 PQ.
+    #[must_use]
+    pub fn new(av1_data: Vec<u8>, width: u32, height: u32, depth_bits: u8, metadata: hdr::GainMapMetadata) -> Self {
+        Self {
+            av1_data,
+            width,
+            height,
+            depth_bits,
+            monochrome: true,
+            chroma_subsampling: (false, false),
+            metadata,
+            alternate_colr: ColrBox {
+                color_primaries: constants::ColorPrimaries::Bt2020,
+                transfer_characteristics: constants::TransferCharacteristics::Smpte2084,
+                matrix_coefficients: constants::MatrixCoefficients::Unspecified,
+                full_range_flag: true,
+            },
+            alternate_clli: None,
+            alternate_depth_bits: 10,
+        }
+    }
+
+    /// Whether the gain map has been encoded as a single channel (the default), or has per-channel gains.
+    #[inline]
+    pub fn set_monochrome(&mut self, monochrome: bool) -> &mut Self {
+        self.monochrome = monochrome;
+        self
+    }
+
+    /// Chroma subsampling of a non-monochrome gain map, same as [`Aviffy::set_chroma_subsampling`].
+    #[inline]
+    pub fn set_chroma_subsampling(&mut self, subsampled_xy: (bool, bool)) -> &mut Self {
+        self.chroma_subsampling = subsampled_xy;
+        self
+    }
+
+    /// Color space of the alternate image
+    #[inline]
+    pub fn set_alternate_color(&mut self, color_primaries: constants::ColorPrimaries, transfer_characteristics: constants::TransferCharacteristics) -> &mut Self {
+        self.alternate_colr.color_primaries = color_primaries;
+        self.alternate_colr.transfer_characteristics = transfer_characteristics;
+        self
+    }
+
+    /// Bit depth the alternate image should be displayed at
+    #[inline]
+    pub fn set_alternate_bit_depth(&mut self, depth_bits: u8) -> &mut Self {
+        self.alternate_depth_bits = depth_bits;
+        self
+    }
+
+    /// Content light level of the alternate image, same as [`Aviffy::set_content_light_level`].
+    #[inline]
+    pub fn set_alternate_content_light_level(&mut self, max_content_light_level: u16, max_pic_average_light_level: u16) -> &mut Self {
+        self.alternate_clli = Some(ClliBox {
+            max_content_light_level,
+            max_pic_average_light_level,
+        });
+        self
+    }
+
+    fn av1c(&self) -> Av1CBox {
+        let (subsampling_x, subsampling_y) = if self.monochrome { (true, true) } else { self.chroma_subsampling };
+        Av1CBox {
+            seq_profile: if self.depth_bits >= 12 || (subsampling_x && !subsampling_y) {
+                2
+            } else if !subsampling_x {
+                1
+            } else {
+                0
+            },
+            seq_level_idx_0: 31,
+            seq_tier_0: false,
+            high_bitdepth: self.depth_bits >= 10,
+            twelve_bit: self.depth_bits >= 12,
+            monochrome: self.monochrome,
+            chroma_subsampling_x: subsampling_x,
+            chroma_subsampling_y: subsampling_y,
+            chroma_sample_position: 0,
+        }
+    }
+}
+
+/// Another rendition of the same image, e.g. HDR version of an SDR image, or a 10-bit version of an 8-bit image. See [`Aviffy::add_alternative`].
+///
+/// It has the same size, chroma subsampling, and transformations as the image, but can have its own depth and color space.
+#[derive(Debug, Clone)]
+pub struct Alternative {
+    av1_data: Vec<u8>,
+    alpha_av1_data: Option<Vec<u8>>,
+    depth_bits: u8,
+    colr: ColrBox,
+    clli: Option<ClliBox>,
+    mdcv: Option<MdcvBox>,
+}
+
+impl Alternative {
+    /// Already-encoded AV1 image, with `depth_bits` 8, 10 or 12. The color space defaults to sRGB, like in [`Aviffy`].
+    #[must_use]
+    pub fn new(av1_data: Vec<u8>, depth_bits: u8) -> Self {
+        Self {
+            av1_data,
+            alpha_av1_data: None,
+            depth_bits,
+            colr: ColrBox::default(),
+            clli: None,
+            mdcv: None,
+        }
+    }
+
+    /// Monochrome image representing transparency of this rendition, with the same depth
+    #[inline]
+    pub fn set_alpha(&mut self, alpha_av1_data: Vec<u8>) -> &mut Self {
+        self.alpha_av1_data = Some(alpha_av1_data);
+        self
+    }
+
+    /// Color space of this rendition. Must match the AV1 payload.
+    #[inline]
+    pub fn set_color(&mut self, color_primaries: constants::ColorPrimaries, transfer_characteristics: constants::TransferCharacteristics, matrix_coefficients: constants::MatrixCoefficients) -> &mut Self {
+        self.colr.color_primaries = color_primaries;
+        self.colr.transfer_characteristics = transfer_characteristics;
+        self.colr.matrix_coefficients = matrix_coefficients;
+        self
+    }
+
+    /// Same as [`Aviffy::set_full_color_range`]
+    #[inline]
+    pub fn set_full_color_range(&mut self, full_range: bool) -> &mut Self {
+        self.colr.full_range_flag = full_range;
+        self
+    }
+
+    /// Same as [`Aviffy::set_content_light_level`]
+    #[inline]
+    pub fn set_content_light_level(&mut self, max_content_light_level: u16, max_pic_average_light_level: u16) -> &mut Self {
+        self.clli = Some(ClliBox {
+            max_content_light_level,
+            max_pic_average_light_level,
+        });
+        self
+    }
+
+    /// Same as [`Aviffy::set_mastering_display_metadata`]
+    #[inline]
+    pub fn set_mastering_display_metadata(&mut self, display: &hdr::MasteringDisplay) -> &mut Self {
+        self.mdcv = Some(display.mdcv());
+        self
+    }
+}
+
+/// Monochrome `av01` item with extra information about the primary image, like alpha
+#[derive(Debug, Clone)]
+struct AuxiliaryImage {
+    urn: &'static str,
+    av1_data: Vec<u8>,
+    width: u32,
+    height: u32,
+    depth_bits: u8,
+    /// Written in `auxC` after the URN
+    aux_subtype: Vec<u8>,
+    /// Metadata item about the auxiliary image
+    xmp: Option<Vec<u8>>,
+}
+
+/// Small preview of the primary image
+#[derive(Debug, Clone)]
+struct Thumbnail {
+    av1_data: Vec<u8>,
+    alpha_av1_data: Option<Vec<u8>>,
+    width: u32,
+    height: u32,
+    depth_bits: u8,
+}
+
+/// How sample values of a depth map translate to distances. See [`Aviffy::set_depth_map`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DepthRepresentationType {
+    /// Values are uniformly quantized 1/Z, between `z_near` and `z_far`
+    UniformInverseZ = 0,
+    /// Values are uniformly quantized disparity, between `d_min` and `d_max`
+    UniformDisparity = 1,
+    /// Values are uniformly quantized Z, between `z_near` and `z_far`
+    UniformZ = 2,
+}
+
+/// Depth representation information (ISO/IEC 23008-2 SEI) for a depth map. See [`Aviffy::set_depth_map`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DepthRepresentation {
+    pub representation_type: DepthRepresentationType,
+    /// Distance of the nearest depth plane
+    pub z_near: Option<f64>,
+    /// Distance of the farthest depth plane
+    pub z_far: Option<f64>,
+    /// Minimum disparity, in units of the luma sample width
+    pub d_min: Option<f64>,
+    /// Maximum disparity, in units of the luma sample width
+    pub d_max: Option<f64>,
+    /// View that the disparity values are relative to
+    pub disparity_reference_view: u32,
+}
+
+impl DepthRepresentation {
+    /// `depth_representation_info` SEI message in a length-prefixed HEVC NAL unit, the way HEIF stores it in `auxC`
+    fn aux_subtype(&self) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        for flag in [self.z_near, self.z_far, self.d_min, self.d_max] {
+            bits.push(u32::from(flag.is_some()), 1);
+        }
+        bits.push_ue(self.representation_type as u32);
+        if self.d_min.is_some() || self.d_max.is_some() {
+            bits.push_ue(self.disparity_reference_view);
+        }
+        for value in [self.z_near, self.z_far, self.d_min, self.d_max].into_iter().flatten() {
+            // sign, 7-bit exponent biased by 31, and 24-bit mantissa of an implicit 1.mantissa
+            const MANTISSA_BITS: u8 = 24;
+            let magnitude = value.abs();
+            let (exponent, mantissa) = if magnitude == 0. || !magnitude.is_finite() {
+                (0, 0.)
+            } else {
+                let exponent = magnitude.log2().floor() as i32 + 31;
+                if exponent < 1 {
+                    // denormal
+                    (0, magnitude * 2_f64.powi(30 + i32::from(MANTISSA_BITS)))
+                } else if exponent > 126 {
+                    (126, f64::from(1_u32 << MANTISSA_BITS) - 1.)
+                } else {
+                    (exponent, (magnitude / 2_f64.powi(exponent - 31) - 1.) * f64::from(1_u32 << MANTISSA_BITS))
+                }
+            };
+            bits.push(u32::from(value.is_sign_negative() && magnitude != 0.), 1);
+            bits.push(exponent as u32, 7);
+            bits.push(u32::from(MANTISSA_BITS - 1), 5);
+            bits.push((mantissa.round() as u32).min((1 << MANTISSA_BITS) - 1), MANTISSA_BITS);
+        }
+        // payload_bit_equal_to_one, then alignment, only if the payload doesn't end at a byte boundary
+        if !bits.is_byte_aligned() {
+            bits.push(1, 1);
+        }
+        let payload = bits.into_bytes();
+
+        let mut rbsp = vec![177, payload.len() as u8]; // depth_representation_info payload type and size
+        rbsp.extend_from_slice(&payload);
+        rbsp.push(0x80); // rbsp_trailing_bits
+
+        let mut nal = vec![39 << 1, 1]; // prefix SEI NAL header
+        let mut zeros = 0;
+        for byte in rbsp {
+            if zeros >= 2 && byte <= 3 {
+                nal.push(3); // emulation prevention
+                zeros = 0;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            nal.push(byte);
+        }
+        let mut out = (nal.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(&nal);
+        out
+    }
+}
+
+/// MSB-first bit packing for SEI payloads
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used_bits: u8,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, bits: u8) {
+        for i in (0..bits).rev() {
+            if self.used_bits % 8 == 0 {
+                self.bytes.push(0);
+                self.used_bits = 0;
+            }
+            if let Some(last) = self.bytes.last_mut() {
+                *last |= (((value >> i) & 1) as u8) << (7 - self.used_bits);
+            }
+            self.used_bits += 1;
+        }
+    }
+
+    /// Exp-Golomb
+    fn push_ue(&mut self, value: u32) {
+        let value = u64::from(value) + 1;
+        let len = 64 - value.leading_zeros() as u8;
+        self.push(0, len - 1);
+        for i in (0..len).rev() {
+            self.push(((value >> i) & 1) as u32, 1);
+        }
+    }
+
+    fn is_byte_aligned(&self) -> bool {
+        self.used_bits % 8 == 0
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Coded data of the primary item
+#[derive(Copy, Clone)]
+enum ImageData<'data> {
+    /// A single AV1 image
+    Coded(&'data [u8]),
+    /// AV1 tiles of a `grid`
+    Grid(&'data GridLayout, &'data [&'data [u8]]),
+    /// AV1 layers of an `iovl`
+    Overlay(&'data Overlay<'data>),
+}
+
+impl ImageData<'_> {
+    fn item_type(&self) -> FourCC {
+        FourCC(match self {
+            Self::Coded(_) => *b"av01",
+            Self::Grid(..) => *b"grid",
+            Self::Overlay(_) => *b"iovl",
+        })
+    }
+}
+
+/// Writes an animated AVIF incrementally, one fragment (`moof` + `mdat`) at a time.
+///
+/// Created with [`Aviffy::fragmented_writer`]. There's nothing to finalize, the file is complete after every fragment.
+pub struct FragmentedWriter<W> {
+    out: W,
+    has_alpha: bool,
+    sequence_number: u32,
+    /// Sum of durations of frames written so far
+    decode_time: u64,
+}
+
+impl<W: io::Write> FragmentedWriter<W> {
+    /// Appends frames to the animation. A fragment can have any number of frames, but it must start with a keyframe,
+    /// so that playback can start from any fragment.
+    ///
+    /// `alpha_frames` must be given if, and only if, the writer has been created with `has_alpha`.
+    /// There must be one alpha frame for every color frame, with the same duration.
+    pub fn write_fragment(&mut self, frames: &[AnimationFrame<'_>], alpha_frames: Option<&[AnimationFrame<'_>]>) -> io::Result<()> {
+        if alpha_frames.is_some() != self.has_alpha {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha frames must be given for every fragment if has_alpha is set"));
+        }
+        if let Some(alpha_frames) = alpha_frames {
+            if alpha_frames.len() != frames.len() || alpha_frames.iter().zip(frames).any(|(a, c)| a.duration != c.duration) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha frames must match color frames"));
+            }
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+        if !(frames[0].is_keyframe && alpha_frames.is_none_or(|a| a[0].is_keyframe)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the first frame of a fragment must be a keyframe"));
+        }
+
+        self.sequence_number = self.sequence_number.checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many fragments"))?;
+        let mut tracks = ArrayVec::new();
+        tracks.push(TrafBox { track_id: 1, base_media_decode_time: self.decode_time, data_offset: 0, samples: frames });
+        if let Some(alpha_frames) = alpha_frames {
+            tracks.push(TrafBox { track_id: 2, base_media_decode_time: self.decode_time, data_offset: 0, samples: alpha_frames });
+        }
+        MoofBox { sequence_number: self.sequence_number, tracks }.write_with_mdat(&mut self.out)?;
+
+        self.decode_time += frames.iter().map(|f| u64::from(f.duration)).sum::<u64>();
+        Ok(())
+    }
+
+    /// Returns the underlying writer
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Splits AV1 data of a layered (progressive) image into layers, for [`Aviffy::set_progressive_layers`].
+///
+/// A new layer starts at every temporal delimiter OBU, and at every change of the spatial layer ID in OBU extension headers.
+/// Returns a single size if the data isn't layered.
+pub fn av1_layer_sizes(av1_data: &[u8]) -> io::Result<Vec<u32>> {
+    let mut sizes = Vec::new();
+    let mut layer_start = 0;
+    let mut spatial_id = None;
+    for obu in obu::obus(av1_data) {
+        let obu = obu?;
+        let new_layer = if obu.obu_type == obu::OBU_TEMPORAL_DELIMITER {
+            spatial_id = None;
+            true
+        } else {
+            let new = spatial_id.is_some() && obu.spatial_id.is_some() && obu.spatial_id != spatial_id;
+            spatial_id = obu.spatial_id.or(spatial_id);
+            new
+        };
+        if new_layer && obu.offset > layer_start {
+            sizes.push(u32::try_from(obu.offset - layer_start).map_err(|_| io::ErrorKind::InvalidInput)?);
+            layer_start = obu.offset;
+        }
+    }
+    sizes.push(u32::try_from(av1_data.len() - layer_start).map_err(|_| io::ErrorKind::InvalidInput)?);
+    Ok(sizes)
 }
 
 /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](https://lib.rs/rav1e))
@@ -69,6 +727,7 @@ impl Aviffy {
     pub fn new() -> Self {
         Self {
             premultiplied_alpha: false,
+            loop_count: None,
             min_seq_profile: 1,
             chroma_subsampling: (false, false),
             monochrome: false,
@@ -76,9 +735,24 @@ impl Aviffy {
             height: 0,
             bit_depth: 0,
             colr: ColrBox::default(),
+            icc: None,
             clli: None,
             mdcv: None,
+            cclv: None,
+            amve: None,
+            crop: None,
+            irot: None,
+            imir: None,
+            pasp: None,
+            iscl: None,
             exif: None,
+            xmp: None,
+            gain_map: None,
+            auxiliary_images: Vec::new(),
+            thumbnails: Vec::new(),
+            alternatives: Vec::new(),
+            layer_sizes: Vec::new(),
+            a1op: None,
         }
     }
 
@@ -135,6 +809,44 @@ impl Aviffy {
         self.set_full_color_range(full_range)
     }
 
+    /// Embed an ICC color profile. Use this for images that can't be described by the CICP values
+    /// ([`Self::set_color_primaries`], [`Self::set_transfer_characteristics`]), such as images in print or custom color spaces.
+    ///
+    /// The profile is written in addition to CICP values, which still describe the AV1 payload's matrix coefficients and range.
+    /// Decoders that support ICC will use the profile instead of the CICP primaries and transfer characteristics.
+    ///
+    /// Adds a `colr` property box of type `prof` to the AVIF container.
+    #[inline]
+    pub fn set_icc_profile(&mut self, icc_profile: Vec<u8>) -> &mut Self {
+        self.icc = Some(ColrIccBox { restricted: false, icc_profile });
+        self
+    }
+
+    /// Same as [`Self::set_icc_profile`], but for restricted ICC profiles (matrix/TRC-based or monochrome, per ISO 15076-1),
+    /// which are simpler for decoders to support.
+    ///
+    /// Adds a `colr` property box of type `rICC` to the AVIF container.
+    #[inline]
+    pub fn set_restricted_icc_profile(&mut self, icc_profile: Vec<u8>) -> &mut Self {
+        self.icc = Some(ColrIccBox { restricted: true, icc_profile });
+        self
+    }
+
+    /// Same as [`Self::set_icc_profile`], but if the profile is equivalent to CICP values (see [`constants::cicp_from_icc_profile`]),
+    /// only sets the color primaries and transfer characteristics, so that the file gets just the compact `nclx` instead of the whole profile.
+    ///
+    /// Matrix coefficients and range aren't changed, because they describe how the AV1 payload has been encoded, not the profile.
+    #[inline]
+    pub fn set_icc_profile_or_cicp(&mut self, icc_profile: Vec<u8>) -> &mut Self {
+        match constants::cicp_from_icc_profile(&icc_profile) {
+            Some((color_primaries, transfer_characteristics, _, _)) => {
+                self.icc = None;
+                self.set_color_primaries(color_primaries).set_transfer_characteristics(transfer_characteristics)
+            },
+            None => self.set_icc_profile(icc_profile),
+        }
+    }
+
     /// Set Content Light Level Information for HDR (CEA-861.3).
     ///
     /// `max_content_light_level` (MaxCLL) is the maximum light level of any single pixel in cd/m².
@@ -171,69 +883,217 @@ impl Aviffy {
         self
     }
 
-    /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](https://lib.rs/rav1e))
-    ///
-    /// `color_av1_data` is already-encoded AV1 image data for the color channels (YUV, RGB, etc.).
-    /// The color image should have been encoded without chroma subsampling AKA YUV444 (`Cs444` in `rav1e`)
-    /// AV1 handles full-res color so effortlessly, you should never need chroma subsampling ever again.
-    ///
-    /// Optional `alpha_av1_data` is a monochrome image (`rav1e` calls it "YUV400"/`Cs400`) representing transparency.
-    /// Alpha adds a lot of header bloat, so don't specify it unless it's necessary.
-    ///
-    /// `width`/`height` is image size in pixels. It must of course match the size of encoded image data.
-    /// `depth_bits` should be 8, 10 or 12, depending on how the image has been encoded in AV1.
+    /// Set Content Colour Volume for HDR (ITU-T H.274), describing the content rather than the mastering display.
     ///
-    /// Color and alpha must have the same dimensions and depth.
+    /// `primaries` are the content primaries in CIE 1931 xy × 50000, in \[green, blue, red\] order like [`Self::set_mastering_display`].
+    /// Luminance values are in cd/m² × 10_000_000 (e.g. 100 cd/m² = 1_000_000_000), so they can't exceed 429 cd/m².
+    /// At least one of the values must be `Some`.
     ///
-    /// Data is written (streamed) to `into_output`.
+    /// Adds a `cclv` property box to the AVIF container.
     #[inline]
-    pub fn write<W: io::Write>(&self, into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> io::Result<()> {
-        self.make_boxes(color_av1_data, alpha_av1_data, width, height, depth_bits)?.write(into_output)
+    pub fn set_content_color_volume(&mut self, primaries: Option<[(i32, i32); 3]>, min_luminance: Option<u32>, max_luminance: Option<u32>, avg_luminance: Option<u32>) -> &mut Self {
+        self.cclv = Some(CclvBox {
+            primaries,
+            min_luminance,
+            max_luminance,
+            avg_luminance,
+        });
+        self
+    }
+
+    /// Set Ambient Viewing Environment (ITU-T H.274) the content was graded for.
+    ///
+    /// `ambient_illuminance` is in lux × 10000 (e.g. 5 lux = 50000), and must be non-zero.
+    /// `ambient_light` is the chromaticity of the ambient light in CIE 1931 xy × 50000 (e.g. D65 = `(15635, 16450)`).
+    ///
+    /// Adds an `amve` property box to the AVIF container.
+    #[inline]
+    pub fn set_ambient_viewing_environment(&mut self, ambient_illuminance: u32, ambient_light: (u16, u16)) -> &mut Self {
+        self.amve = Some(AmveBox {
+            ambient_illuminance,
+            ambient_light,
+        });
+        self
+    }
+
+    /// Crop the image when displayed to a `width`×`height` rectangle with top-left corner at `x`,`y`.
+    ///
+    /// This is useful for images with odd dimensions when using chroma subsampling, which need to be encoded with even dimensions.
+    /// With chroma subsampling `x` (and `y` for 4:2:0) must be even, but the size doesn't have to be.
+    ///
+    /// Width and height of the image are still the size of the encoded image, before the crop.
+    /// The crop rectangle must be within the image, and is applied before rotation and mirroring.
+    ///
+    /// Adds a `clap` property box to the AVIF container.
+    #[inline]
+    pub fn set_crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> &mut Self {
+        self.crop = Some([x, y, width, height]);
+        self
+    }
+
+    /// Set Content Light Level Information for HDR (CEA-861.3), measured from the frames by [`hdr::ContentLightLevel`].
+    ///
+    /// Same as [`Self::set_content_light_level`].
+    #[inline]
+    pub fn set_content_light_level_metadata(&mut self, light_level: &hdr::ContentLightLevel) -> &mut Self {
+        self.clli = Some(light_level.clli());
+        self
+    }
+
+    /// Set Mastering Display Colour Volume for HDR (SMPTE ST 2086), from human-friendly values. See [`hdr::MasteringDisplay`] for presets.
+    ///
+    /// Same as [`Self::set_mastering_display`], but converts and rounds the values to the encoding used by the `mdcv` box.
+    #[inline]
+    pub fn set_mastering_display_metadata(&mut self, display: &hdr::MasteringDisplay) -> &mut Self {
+        self.mdcv = Some(display.mdcv());
+        self
+    }
+
+    /// Rotate the image when displayed, without re-encoding it. Rotation is anti-clockwise, and is applied before mirroring.
+    ///
+    /// Width and height are still the size of the encoded image, before the rotation.
+    ///
+    /// Adds an `irot` property box to the AVIF container (unless it's [`Rotation::D0`]).
+    #[inline]
+    pub fn set_rotation(&mut self, rotation: Rotation) -> &mut Self {
+        self.irot = Some(IrotBox { angle: rotation as u8 }).filter(|irot| irot.angle != 0);
+        self
+    }
+
+    /// Mirror the image when displayed, without re-encoding it. Mirroring is applied after rotation.
+    ///
+    /// Adds an `imir` property box to the AVIF container.
+    #[inline]
+    pub fn set_mirror(&mut self, axis: Option<MirrorAxis>) -> &mut Self {
+        self.imir = axis.map(|axis| ImirBox { axis: axis as u8 });
+        self
+    }
+
+    /// Set the pixel aspect ratio for images with non-square pixels, e.g. from anamorphic video.
+    /// A pixel is `h_spacing` wide and `v_spacing` tall (in arbitrary units), so `(4, 3)` makes pixels wider than tall.
+    ///
+    /// Adds a `pasp` property box to the AVIF container, unless the pixels are square, e.g. `(1, 1)`, which is the default.
+    /// Spacing of 0 is an error when writing.
+    #[inline]
+    pub fn set_pixel_aspect_ratio(&mut self, h_spacing: u32, v_spacing: u32) -> &mut Self {
+        self.pasp = Some(PaspBox { h_spacing, v_spacing });
+        self
+    }
+
+    /// Hint that the image should be displayed scaled, e.g. `(2, 1)` for twice the encoded width.
+    /// Both are fractions (numerator, denominator) of the size after cropping and rotation.
+    ///
+    /// Adds an `iscl` property box to the AVIF container. It's a transformation like cropping and rotation, so it's marked as essential,
+    /// and decoders that don't support it won't display the image. MIAF (ISO/IEC 23000-22) allows only `clap`, `irot` and `imir`
+    /// transformations, so MIAF readers may reject such files.
+    #[inline]
+    pub fn set_scaling(&mut self, width_ratio: (u16, u16), height_ratio: (u16, u16)) -> &mut Self {
+        self.iscl = Some(IsclBox { target_width: width_ratio, target_height: height_ratio });
+        self
+    }
+
+    /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](https://lib.rs/rav1e))
+    ///
+    /// `color_av1_data` is already-encoded AV1 image data for the color channels (YUV, RGB, etc.).
+    /// The color image should have been encoded without chroma subsampling AKA YUV444 (`Cs444` in `rav1e`)
+    /// AV1 handles full-res color so effortlessly, you should never need chroma subsampling ever again.
+    ///
+    /// Optional `alpha_av1_data` is a monochrome image (`rav1e` calls it "YUV400"/`Cs400`) representing transparency.
+    /// Alpha adds a lot of header bloat, so don't specify it unless it's necessary.
+    ///
+    /// `width`/`height` is image size in pixels. It must of course match the size of encoded image data.
+    /// `depth_bits` should be 8, 10 or 12, depending on how the image has been encoded in AV1.
+    ///
+    /// Color and alpha must have the same dimensions and depth.
+    ///
+    /// Data is written (streamed) to `into_output`.
+    #[inline]
+    pub fn write<W: io::Write>(&self, into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> io::Result<()> {
+        self.make_boxes(ImageData::Coded(color_av1_data), alpha_av1_data.map(ImageData::Coded), width, height, depth_bits)?.write(into_output)
     }
 
     /// See [`Self::write`]
     #[inline]
     pub fn write_slice<W: io::Write>(&self, into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>) -> io::Result<()> {
-        self.make_boxes(color_av1_data, alpha_av1_data, self.width, self.height, self.bit_depth)?.write(into_output)
+        self.make_boxes(ImageData::Coded(color_av1_data), alpha_av1_data.map(ImageData::Coded), self.width, self.height, self.bit_depth)?.write(into_output)
+    }
+
+    /// Makes an AVIF file with a `grid` derived image, for images that are too large to be encoded as a single AV1 frame.
+    ///
+    /// The image is split according to `layout` (see [`GridLayout::plan`]), and each tile is encoded separately.
+    /// `color_tiles` are the already-encoded AV1 tiles in row-major order. All tiles must have the same dimensions and depth.
+    ///
+    /// Optional `alpha_tiles` are monochrome tiles representing transparency, split the same way as the color tiles.
+    /// They're written as an alpha grid, the tiled equivalent of `alpha_av1_data` in [`Self::write`].
+    ///
+    /// Width, height and bit depth of the whole image must have been set with [`Self::set_width`], [`Self::set_height`], and [`Self::set_bit_depth`].
+    ///
+    /// Data is written (streamed) to `into_output`.
+    #[inline]
+    pub fn write_grid<W: io::Write>(&self, into_output: W, layout: &GridLayout, color_tiles: &[&[u8]], alpha_tiles: Option<&[&[u8]]>) -> io::Result<()> {
+        self.make_boxes(ImageData::Grid(layout, color_tiles), alpha_tiles.map(|tiles| ImageData::Grid(layout, tiles)), self.width, self.height, self.bit_depth)?.write(into_output)
     }
 
-    fn make_boxes<'data>(&'data self, color_av1_data: &'data [u8], alpha_av1_data: Option<&'data [u8]>, width: u32, height: u32, depth_bits: u8) -> io::Result<AvifFile<'data>> {
+    /// Panics if the input arguments were invalid. Use [`Self::write_grid`] to handle the errors.
+    #[must_use]
+    #[track_caller]
+    pub fn grid_to_vec(&self, layout: &GridLayout, color_tiles: &[&[u8]], alpha_tiles: Option<&[&[u8]]>) -> Vec<u8> {
+        let mut file = self.make_boxes(ImageData::Grid(layout, color_tiles), alpha_tiles.map(|tiles| ImageData::Grid(layout, tiles)), self.width, self.height, self.bit_depth).unwrap();
+        let mut out = Vec::new();
+        file.write_to_vec(&mut out).unwrap();
+        out
+    }
+
+    /// Makes an AVIF file with an `iovl` derived image, which composes several separately-encoded images on a canvas.
+    ///
+    /// The canvas has the width and height set with [`Self::set_width`] and [`Self::set_height`]. All layers must have the depth set with [`Self::set_bit_depth`].
+    ///
+    /// Data is written (streamed) to `into_output`.
+    #[inline]
+    pub fn write_overlay<W: io::Write>(&self, into_output: W, overlay: &Overlay<'_>) -> io::Result<()> {
+        self.make_boxes(ImageData::Overlay(overlay), None, self.width, self.height, self.bit_depth)?.write(into_output)
+    }
+
+    /// Panics if the input arguments were invalid. Use [`Self::write_overlay`] to handle the errors.
+    #[must_use]
+    #[track_caller]
+    pub fn overlay_to_vec(&self, overlay: &Overlay<'_>) -> Vec<u8> {
+        let mut file = self.make_boxes(ImageData::Overlay(overlay), None, self.width, self.height, self.bit_depth).unwrap();
+        let mut out = Vec::new();
+        file.write_to_vec(&mut out).unwrap();
+        out
+    }
+
+    fn make_boxes<'data>(&'data self, color: ImageData<'data>, alpha: Option<ImageData<'data>>, width: u32, height: u32, depth_bits: u8) -> io::Result<AvifFile<'data>> {
         if ![8, 10, 12].contains(&depth_bits) {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "depth must be 8/10/12"));
         }
 
-        let mut image_items = ArrayVec::new();
-        let mut iloc_items = ArrayVec::new();
-        let mut ipma_entries = ArrayVec::new();
-        let mut irefs = ArrayVec::new();
+        let mut image_items = Vec::new();
+        let mut iloc_items = Vec::new();
+        let mut idat_items = Vec::new();
+        let mut ipma_entries = Vec::new();
+        let mut irefs = Vec::new();
         let mut ipco = IpcoBox::new();
         let color_image_id = 1;
         let alpha_image_id = 2;
         let exif_id = 3;
-        const ESSENTIAL_BIT: u8 = 0x80;
+        let first_tile_id = 4_u16;
         let color_depth_bits = depth_bits;
         let alpha_depth_bits = depth_bits; // Sadly, the spec requires these to match.
 
         image_items.push(InfeBox {
             id: color_image_id,
-            typ: FourCC(*b"av01"),
+            typ: color.item_type(),
             name: "",
+            content_type: "",
+            hidden: false,
         });
 
         let ispe_prop = ipco.push(IpcoProp::Ispe(IspeBox { width, height })).ok_or(io::ErrorKind::InvalidInput)?;
 
         // This is redundant, but Chrome wants it, and checks that it matches :(
-        let av1c_color_prop = ipco.push(IpcoProp::Av1C(Av1CBox {
-            seq_profile: self.min_seq_profile.max(if color_depth_bits >= 12 { 2 } else { 0 }),
-            seq_level_idx_0: 31,
-            seq_tier_0: false,
-            high_bitdepth: color_depth_bits >= 10,
-            twelve_bit: color_depth_bits >= 12,
-            monochrome: self.monochrome,
-            chroma_subsampling_x: self.chroma_subsampling.0,
-            chroma_subsampling_y: self.chroma_subsampling.1,
-            chroma_sample_position: 0,
-        })).ok_or(io::ErrorKind::InvalidInput)?;
+        let av1c_color_prop = ipco.push(IpcoProp::Av1C(self.color_av1c(color_depth_bits))).ok_or(io::ErrorKind::InvalidInput)?;
 
         // Useless bloat
         let pixi_3 = ipco.push(IpcoProp::Pixi(PixiBox {
@@ -241,16 +1101,33 @@ impl Aviffy {
             depth: color_depth_bits,
         })).ok_or(io::ErrorKind::InvalidInput)?;
 
+        // Redundant info, already in AV1
+        let colr_color_prop = if self.colr != ColrBox::default() {
+            Some(ipco.push(IpcoProp::Colr(self.colr)).ok_or(io::ErrorKind::InvalidInput)?)
+        } else {
+            None
+        };
+
         let mut ipma = IpmaEntry {
             item_id: color_image_id,
-            prop_ids: from_array([ispe_prop, av1c_color_prop | ESSENTIAL_BIT, pixi_3]),
+            prop_ids: match color {
+                ImageData::Coded(_) => vec![ispe_prop, av1c_color_prop | ESSENTIAL_BIT, pixi_3],
+                // Derived images aren't AV1 themselves, only their inputs are
+                ImageData::Grid(..) | ImageData::Overlay(_) => vec![ispe_prop, pixi_3],
+            },
         };
+        ipma.prop_ids.extend(colr_color_prop);
 
-        // Redundant info, already in AV1
-        if self.colr != ColrBox::default() {
-            let colr_color_prop = ipco.push(IpcoProp::Colr(self.colr)).ok_or(io::ErrorKind::InvalidInput)?;
-            ipma.prop_ids.push(colr_color_prop);
-        }
+        // HEIF allows one of each colr type
+        let icc_color_prop = if let Some(icc) = &self.icc {
+            if icc.icc_profile.get(36..40) != Some(b"acsp") {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid ICC profile"));
+            }
+            Some(ipco.push(IpcoProp::ColrIcc(icc.clone())).ok_or(io::ErrorKind::InvalidInput)?)
+        } else {
+            None
+        };
+        ipma.prop_ids.extend(icc_color_prop);
 
         if let Some(clli) = self.clli {
             let clli_prop = ipco.push(IpcoProp::Clli(clli)).ok_or(io::ErrorKind::InvalidInput)?;
@@ -262,13 +1139,158 @@ impl Aviffy {
             ipma.prop_ids.push(mdcv_prop);
         }
 
+        if let Some(cclv) = self.cclv {
+            let luminance = [cclv.min_luminance, cclv.avg_luminance, cclv.max_luminance];
+            let valid_primaries = cclv.primaries.into_iter().flatten().all(|(x, y)| (-5_000_000..=5_000_000).contains(&x) && (-5_000_000..=5_000_000).contains(&y));
+            let ascending = luminance.iter().flatten().is_sorted();
+            if (cclv.primaries.is_none() && luminance.iter().all(Option::is_none)) || !valid_primaries || !ascending {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid content color volume"));
+            }
+            let cclv_prop = ipco.push(IpcoProp::Cclv(cclv)).ok_or(io::ErrorKind::InvalidInput)?;
+            ipma.prop_ids.push(cclv_prop);
+        }
+
+        if let Some(amve) = self.amve {
+            if amve.ambient_illuminance == 0 || amve.ambient_light.0 > 50000 || amve.ambient_light.1 > 50000 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid ambient viewing environment"));
+            }
+            let amve_prop = ipco.push(IpcoProp::Amve(amve)).ok_or(io::ErrorKind::InvalidInput)?;
+            ipma.prop_ids.push(amve_prop);
+        }
+
+        if let Some(pasp) = self.pasp {
+            if pasp.h_spacing == 0 || pasp.v_spacing == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel aspect ratio must be > 0"));
+            }
+        }
+        if let Some(pasp) = self.pasp.filter(|p| p.h_spacing != p.v_spacing) {
+            let pasp_prop = ipco.push(IpcoProp::Pasp(pasp)).ok_or(io::ErrorKind::InvalidInput)?;
+            ipma.prop_ids.push(pasp_prop);
+        }
+
+        let layered = !self.layer_sizes.is_empty();
+        if layered {
+            let ImageData::Coded(color_av1_data) = color else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "only single images can be progressive"));
+            };
+            let total = self.layer_sizes.iter().try_fold(0_usize, |sum, &s| sum.checked_add(s as usize).filter(|_| s > 0));
+            if !(2..=4).contains(&self.layer_sizes.len()) || total != Some(color_av1_data.len()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "progressive image needs 2-4 non-empty layers adding up to the data length"));
+            }
+            let mut layer_sizes = [0; 3];
+            for (dst, &src) in layer_sizes.iter_mut().zip(&self.layer_sizes[..self.layer_sizes.len() - 1]) {
+                *dst = src;
+            }
+            let a1lx_prop = ipco.push(IpcoProp::A1lx(A1lxBox { layer_sizes })).ok_or(io::ErrorKind::InvalidInput)?;
+            let lsel_prop = ipco.push(IpcoProp::Lsel(LselBox { layer_id: 0xFFFF })).ok_or(io::ErrorKind::InvalidInput)?;
+            ipma.prop_ids.extend([a1lx_prop, lsel_prop | ESSENTIAL_BIT]);
+        }
+
+        if let Some(a1op) = self.a1op {
+            let ImageData::Coded(color_av1_data) = color else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "operating point can be selected only for single images"));
+            };
+            let count = obu::operating_points_count(color_av1_data)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "operating point needs AV1 data with a sequence header"))?;
+            if a1op.op_index >= count {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "operating point not in the AV1 sequence header"));
+            }
+            let a1op_prop = ipco.push(IpcoProp::A1op(a1op)).ok_or(io::ErrorKind::InvalidInput)?;
+            ipma.prop_ids.push(a1op_prop | ESSENTIAL_BIT);
+        }
+
+        // Transformations must be after all descriptive properties, in this order
+        let mut transform_props = Vec::new();
+        // Rotation and mirroring don't depend on the image size, so they apply to thumbnails too
+        let mut orientation_props = Vec::new();
+        if let Some([x, y, crop_width, crop_height]) = self.crop {
+            if (self.chroma_subsampling.0 && x % 2 != 0) || (self.chroma_subsampling.1 && y % 2 != 0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "crop must start at even x/y with chroma subsampling"));
+            }
+            let clap = ClapBox::from_rect(x, y, crop_width, crop_height, width, height)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "crop must be within width/height"))?;
+            transform_props.push(ipco.push(IpcoProp::Clap(clap)).ok_or(io::ErrorKind::InvalidInput)? | ESSENTIAL_BIT);
+        }
+        if let Some(irot) = self.irot {
+            orientation_props.push(ipco.push(IpcoProp::Irot(irot)).ok_or(io::ErrorKind::InvalidInput)? | ESSENTIAL_BIT);
+        }
+        if let Some(imir) = self.imir {
+            orientation_props.push(ipco.push(IpcoProp::Imir(imir)).ok_or(io::ErrorKind::InvalidInput)? | ESSENTIAL_BIT);
+        }
+        transform_props.extend_from_slice(&orientation_props);
+        if let Some(iscl) = self.iscl {
+            if [iscl.target_width, iscl.target_height].iter().any(|&(n, d)| n == 0 || d == 0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "scaling ratio must be > 0"));
+            }
+            // HEIF (ISO/IEC 23008-12) defines iscl as a transformative property, and displaying the image unscaled would be wrong
+            transform_props.push(ipco.push(IpcoProp::Iscl(iscl)).ok_or(io::ErrorKind::InvalidInput)? | ESSENTIAL_BIT);
+        }
+        ipma.prop_ids.extend_from_slice(&transform_props);
+
         ipma_entries.push(ipma);
 
+        // Tile items are added after the primary item's properties, so that non-grid files stay the same
+        let (color_extents, ispe_tile_prop) = match color {
+            ImageData::Coded(color_av1_data) => (vec![IlocItem {
+                id: color_image_id,
+                extents: from_array([IlocExtent { data: color_av1_data }]),
+            }], None),
+            ImageData::Grid(layout, tiles) => {
+                layout.validate(width, height)?;
+                if tiles.len() != layout.tile_count() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "number of tiles doesn't match grid layout"));
+                }
+                idat_items.push(IdatItem {
+                    id: color_image_id,
+                    data: layout.image_grid(width, height),
+                });
+
+                let ispe_tile_prop = ipco.push(IpcoProp::Ispe(IspeBox {
+                    width: layout.tile_width,
+                    height: layout.tile_height,
+                })).ok_or(io::ErrorKind::InvalidInput)?;
+
+                let mut prop_ids = vec![ispe_tile_prop, av1c_color_prop | ESSENTIAL_BIT, pixi_3];
+                prop_ids.extend(colr_color_prop);
+                let tiles = derived_input_items(color_image_id, first_tile_id, tiles.iter().map(|&t| (t, prop_ids.clone())), &mut image_items, &mut ipma_entries, &mut irefs)?;
+                (tiles, Some(ispe_tile_prop))
+            },
+            ImageData::Overlay(overlay) => {
+                if overlay.layers.is_empty() || overlay.layers.iter().any(|l| l.width == 0 || l.height == 0) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "overlay needs layers with width/height"));
+                }
+                idat_items.push(IdatItem {
+                    id: color_image_id,
+                    data: overlay.image_overlay(width, height),
+                });
+
+                // Layers of the same size (as each other or the canvas) share the property
+                let mut ispe_layer_props = vec![((width, height), ispe_prop)];
+                let mut layers = Vec::with_capacity(overlay.layers.len());
+                for l in &overlay.layers {
+                    let ispe_layer_prop = if let Some(&(_, prop)) = ispe_layer_props.iter().find(|(size, _)| *size == (l.width, l.height)) {
+                        prop
+                    } else {
+                        let prop = ipco.push(IpcoProp::Ispe(IspeBox { width: l.width, height: l.height })).ok_or(io::ErrorKind::InvalidInput)?;
+                        ispe_layer_props.push(((l.width, l.height), prop));
+                        prop
+                    };
+                    let mut prop_ids = vec![ispe_layer_prop, av1c_color_prop | ESSENTIAL_BIT, pixi_3];
+                    prop_ids.extend(colr_color_prop);
+                    layers.push((l.av1_data, prop_ids));
+                }
+                let layers = derived_input_items(color_image_id, first_tile_id, layers.into_iter(), &mut image_items, &mut ipma_entries, &mut irefs)?;
+                (layers, None)
+            },
+        };
+
         if let Some(exif_data) = self.exif.as_deref() {
             image_items.push(InfeBox {
                 id: exif_id,
                 typ: FourCC(*b"Exif"),
                 name: "",
+                content_type: "",
+                hidden: false,
             });
 
             iloc_items.push(IlocItem {
@@ -278,85 +1300,354 @@ impl Aviffy {
 
             irefs.push(IrefEntryBox {
                 from_id: exif_id,
-                to_id: color_image_id,
+                to_ids: vec![color_image_id],
                 typ: FourCC(*b"cdsc"),
             });
         }
 
-        if let Some(alpha_data) = alpha_av1_data {
+        if let Some(alpha) = alpha {
+            let alpha_props = self.push_alpha_item(alpha_image_id, alpha.item_type(), color_image_id, alpha_depth_bits, ispe_prop, &transform_props, &mut ipco, &mut image_items, &mut ipma_entries, &mut irefs)?;
+
+            let alpha_extents = match (alpha, color, ispe_tile_prop) {
+                (ImageData::Coded(alpha_data), ImageData::Coded(_), _) => {
+                    vec![IlocItem {
+                        id: alpha_image_id,
+                        extents: from_array([IlocExtent { data: alpha_data }]),
+                    }]
+                },
+                (ImageData::Grid(layout, tiles), ImageData::Grid(color_layout, color_tiles), Some(ispe_tile_prop)) => {
+                    if layout != color_layout || tiles.len() != color_tiles.len() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha grid must have the same layout as the color grid"));
+                    }
+                    idat_items.push(IdatItem {
+                        id: alpha_image_id,
+                        data: layout.image_grid(width, height),
+                    });
+                    // Tiles are auxiliary images too
+                    let mut prop_ids = vec![ispe_tile_prop];
+                    prop_ids.extend_from_slice(&alpha_props);
+                    let first_alpha_tile_id = first_tile_id + color_extents.len() as u16;
+                    derived_input_items(alpha_image_id, first_alpha_tile_id, tiles.iter().map(|&t| (t, prop_ids.clone())), &mut image_items, &mut ipma_entries, &mut irefs)?
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha must be split the same way as color")),
+            };
+
+            // Use interleaved color and alpha, with alpha first.
+            // Makes it possible to display partial image.
+            for (alpha, color) in alpha_extents.into_iter().zip(color_extents) {
+                iloc_items.extend([alpha, color]);
+            }
+        } else {
+            iloc_items.extend(color_extents);
+        }
+
+        // Alternatives that decoders supporting them prefer over the primary item
+        let mut altr_entities = Vec::new();
+        for alt in &self.alternatives {
+            if ![8, 10, 12].contains(&alt.depth_bits) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "alternative depth must be 8/10/12"));
+            }
+            let alt_id = next_item_id(&image_items)?;
             image_items.push(InfeBox {
-                id: alpha_image_id,
+                id: alt_id,
                 typ: FourCC(*b"av01"),
                 name: "",
+                content_type: "",
+                hidden: false,
             });
+            altr_entities.push(u32::from(alt_id));
 
-            irefs.push(IrefEntryBox {
-                from_id: alpha_image_id,
-                to_id: color_image_id,
-                typ: FourCC(*b"auxl"),
-            });
+            let av1c_alt = ipco.push(IpcoProp::Av1C(self.color_av1c(alt.depth_bits))).ok_or(io::ErrorKind::InvalidInput)?;
+            let pixi_alt = ipco.push(IpcoProp::Pixi(PixiBox { channels: 3, depth: alt.depth_bits })).ok_or(io::ErrorKind::InvalidInput)?;
+            let mut prop_ids = vec![ispe_prop, av1c_alt | ESSENTIAL_BIT, pixi_alt];
+            if alt.colr != ColrBox::default() {
+                prop_ids.push(ipco.push(IpcoProp::Colr(alt.colr)).ok_or(io::ErrorKind::InvalidInput)?);
+            }
+            if let Some(clli) = alt.clli {
+                prop_ids.push(ipco.push(IpcoProp::Clli(clli)).ok_or(io::ErrorKind::InvalidInput)?);
+            }
+            if let Some(mdcv) = alt.mdcv {
+                prop_ids.push(ipco.push(IpcoProp::Mdcv(mdcv)).ok_or(io::ErrorKind::InvalidInput)?);
+            }
+            prop_ids.extend_from_slice(&transform_props);
+            ipma_entries.push(IpmaEntry { item_id: alt_id, prop_ids });
 
-            if self.premultiplied_alpha {
-                irefs.push(IrefEntryBox {
-                    from_id: color_image_id,
-                    to_id: alpha_image_id,
-                    typ: FourCC(*b"prem"),
+            let alt_extent = IlocItem {
+                id: alt_id,
+                extents: from_array([IlocExtent { data: &alt.av1_data }]),
+            };
+
+            if let Some(alpha_data) = alt.alpha_av1_data.as_deref() {
+                let alpha_alt_id = next_item_id(&image_items)?;
+                self.push_alpha_item(alpha_alt_id, FourCC(*b"av01"), alt_id, alt.depth_bits, ispe_prop, &transform_props, &mut ipco, &mut image_items, &mut ipma_entries, &mut irefs)?;
+
+                iloc_items.push(IlocItem {
+                    id: alpha_alt_id,
+                    extents: from_array([IlocExtent { data: alpha_data }]),
                 });
             }
+            iloc_items.push(alt_extent);
+        }
+
+        // After the base image, since displays without HDR don't need it
+        if let Some(gain_map) = &self.gain_map {
+            if gain_map.width == 0 || gain_map.height == 0 || ![8, 10, 12].contains(&gain_map.depth_bits) || ![8, 10, 12].contains(&gain_map.alternate_depth_bits) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "gain map needs width/height and depth 8/10/12"));
+            }
+            let tmap_id = next_item_id(&image_items)?;
+            image_items.push(InfeBox {
+                id: tmap_id,
+                typ: FourCC(*b"tmap"),
+                name: "",
+                content_type: "",
+                hidden: false,
+            });
+            idat_items.push(IdatItem {
+                id: tmap_id,
+                data: gain_map.metadata.tone_map_image()?,
+            });
 
-            let av1c_alpha_prop = ipco.push(boxes::IpcoProp::Av1C(Av1CBox {
-                seq_profile: if alpha_depth_bits >= 12 { 2 } else { 0 },
-                seq_level_idx_0: 31,
-                seq_tier_0: false,
-                high_bitdepth: alpha_depth_bits >= 10,
-                twelve_bit: alpha_depth_bits >= 12,
-                monochrome: true,
-                chroma_subsampling_x: true,
-                chroma_subsampling_y: true,
-                chroma_sample_position: 0,
+            let gain_map_id = next_item_id(&image_items)?;
+            image_items.push(InfeBox {
+                id: gain_map_id,
+                typ: FourCC(*b"av01"),
+                name: "",
+                content_type: "",
+                hidden: true,
+            });
+
+            irefs.push(IrefEntryBox {
+                from_id: tmap_id,
+                to_ids: vec![color_image_id, gain_map_id],
+                typ: FourCC(*b"dimg"),
+            });
+
+            // The alternate image is what the tmap item outputs
+            let pixi_alternate = ipco.push(IpcoProp::Pixi(PixiBox {
+                channels: 3,
+                depth: gain_map.alternate_depth_bits,
+            })).ok_or(io::ErrorKind::InvalidInput)?;
+            let colr_alternate = ipco.push(IpcoProp::Colr(gain_map.alternate_colr)).ok_or(io::ErrorKind::InvalidInput)?;
+            let mut prop_ids = vec![ispe_prop, pixi_alternate, colr_alternate];
+            if let Some(clli) = gain_map.alternate_clli {
+                prop_ids.push(ipco.push(IpcoProp::Clli(clli)).ok_or(io::ErrorKind::InvalidInput)?);
+            }
+            prop_ids.extend_from_slice(&transform_props);
+            ipma_entries.push(IpmaEntry { item_id: tmap_id, prop_ids });
+
+            let ispe_gain_map = if (gain_map.width, gain_map.height) == (width, height) {
+                ispe_prop
+            } else {
+                ipco.push(IpcoProp::Ispe(IspeBox { width: gain_map.width, height: gain_map.height })).ok_or(io::ErrorKind::InvalidInput)?
+            };
+            let av1c_gain_map = ipco.push(IpcoProp::Av1C(gain_map.av1c())).ok_or(io::ErrorKind::InvalidInput)?;
+            let pixi_gain_map = ipco.push(IpcoProp::Pixi(PixiBox {
+                channels: if gain_map.monochrome { 1 } else { 3 },
+                depth: gain_map.depth_bits,
             })).ok_or(io::ErrorKind::InvalidInput)?;
+            ipma_entries.push(IpmaEntry {
+                item_id: gain_map_id,
+                prop_ids: vec![ispe_gain_map, av1c_gain_map | ESSENTIAL_BIT, pixi_gain_map],
+            });
+
+            iloc_items.push(IlocItem {
+                id: gain_map_id,
+                extents: from_array([IlocExtent { data: &gain_map.av1_data }]),
+            });
+
+            altr_entities.push(u32::from(tmap_id));
+        }
+
+        for aux in &self.auxiliary_images {
+            if aux.width == 0 || aux.height == 0 || ![8, 10, 12].contains(&aux.depth_bits) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "auxiliary image needs width/height and depth 8/10/12"));
+            }
+            let aux_id = next_item_id(&image_items)?;
+            image_items.push(InfeBox {
+                id: aux_id,
+                typ: FourCC(*b"av01"),
+                name: "",
+                content_type: "",
+                hidden: false,
+            });
+
+            irefs.push(IrefEntryBox {
+                from_id: aux_id,
+                to_ids: vec![color_image_id],
+                typ: FourCC(*b"auxl"),
+            });
 
-            // So pointless
-            let pixi_1 = ipco.push(IpcoProp::Pixi(PixiBox {
+            let ispe_aux = if (aux.width, aux.height) == (width, height) {
+                ispe_prop
+            } else {
+                ipco.push(IpcoProp::Ispe(IspeBox { width: aux.width, height: aux.height })).ok_or(io::ErrorKind::InvalidInput)?
+            };
+            let av1c_aux = ipco.push(IpcoProp::Av1C(alpha_av1c(aux.depth_bits))).ok_or(io::ErrorKind::InvalidInput)?;
+            let auxc_aux = ipco.push(IpcoProp::AuxC(AuxCBox { urn: aux.urn, subtype: aux.aux_subtype.clone() })).ok_or(io::ErrorKind::InvalidInput)?;
+            let pixi_aux = ipco.push(IpcoProp::Pixi(PixiBox {
                 channels: 1,
-                depth: alpha_depth_bits,
+                depth: aux.depth_bits,
             })).ok_or(io::ErrorKind::InvalidInput)?;
+            let mut prop_ids = vec![ispe_aux, av1c_aux | ESSENTIAL_BIT, auxc_aux, pixi_aux];
+            // Auxiliary images must be transformed like the image they belong to
+            if (aux.width, aux.height) == (width, height) {
+                prop_ids.extend_from_slice(&transform_props);
+            } else {
+                // Only the crop depends on the size. It's scaled to the auxiliary image, and must stay on whole pixels.
+                if let Some([x, y, crop_width, crop_height]) = self.crop {
+                    let scale = |v: u32, aux_size: u32, size: u32| {
+                        let v = u64::from(v) * u64::from(aux_size);
+                        (v % u64::from(size) == 0).then(|| u32::try_from(v / u64::from(size)).ok()).flatten()
+                    };
+                    let clap = scale(x, aux.width, width).zip(scale(y, aux.height, height))
+                        .zip(scale(crop_width, aux.width, width).zip(scale(crop_height, aux.height, height)))
+                        .and_then(|((x, y), (crop_width, crop_height))| ClapBox::from_rect(x, y, crop_width, crop_height, aux.width, aux.height))
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "crop must scale to whole pixels of auxiliary images"))?;
+                    prop_ids.push(ipco.push(IpcoProp::Clap(clap)).ok_or(io::ErrorKind::InvalidInput)? | ESSENTIAL_BIT);
+                }
+                // The rest of transformations, after clap
+                prop_ids.extend_from_slice(&transform_props[usize::from(self.crop.is_some())..]);
+            }
+            ipma_entries.push(IpmaEntry { item_id: aux_id, prop_ids });
 
-            // that's a silly way to add 1 bit of information, isn't it?
-            let auxc_prop = ipco.push(IpcoProp::AuxC(AuxCBox {
-                urn: "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
-            })).ok_or(io::ErrorKind::InvalidInput)?;
+            iloc_items.push(IlocItem {
+                id: aux_id,
+                extents: from_array([IlocExtent { data: &aux.av1_data }]),
+            });
 
-            ipma_entries.push(IpmaEntry {
-                item_id: alpha_image_id,
-                prop_ids: from_array([ispe_prop, av1c_alpha_prop | ESSENTIAL_BIT, auxc_prop, pixi_1]),
+            if let Some(xmp_data) = aux.xmp.as_deref() {
+                let xmp_id = next_item_id(&image_items)?;
+                image_items.push(InfeBox {
+                    id: xmp_id,
+                    typ: FourCC(*b"mime"),
+                    name: "",
+                    content_type: "application/rdf+xml",
+                    hidden: false,
+                });
+                iloc_items.push(IlocItem {
+                    id: xmp_id,
+                    extents: from_array([IlocExtent { data: xmp_data }]),
+                });
+                irefs.push(IrefEntryBox {
+                    from_id: xmp_id,
+                    to_ids: vec![aux_id],
+                    typ: FourCC(*b"cdsc"),
+                });
+            }
+        }
+
+        for thumb in &self.thumbnails {
+            if thumb.width == 0 || thumb.height == 0 || ![8, 10, 12].contains(&thumb.depth_bits) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "thumbnail needs width/height and depth 8/10/12"));
+            }
+            let thumb_id = next_item_id(&image_items)?;
+            image_items.push(InfeBox {
+                id: thumb_id,
+                typ: FourCC(*b"av01"),
+                name: "",
+                content_type: "",
+                hidden: false,
+            });
+            irefs.push(IrefEntryBox {
+                from_id: thumb_id,
+                to_ids: vec![color_image_id],
+                typ: FourCC(*b"thmb"),
+            });
+
+            let ispe_thumb = ipco.push(IpcoProp::Ispe(IspeBox { width: thumb.width, height: thumb.height })).ok_or(io::ErrorKind::InvalidInput)?;
+            let (av1c_thumb, pixi_thumb) = if thumb.depth_bits == color_depth_bits {
+                (av1c_color_prop, pixi_3)
+            } else {
+                (
+                    ipco.push(IpcoProp::Av1C(self.color_av1c(thumb.depth_bits))).ok_or(io::ErrorKind::InvalidInput)?,
+                    ipco.push(IpcoProp::Pixi(PixiBox { channels: 3, depth: thumb.depth_bits })).ok_or(io::ErrorKind::InvalidInput)?,
+                )
+            };
+            let mut prop_ids = vec![ispe_thumb, av1c_thumb | ESSENTIAL_BIT, pixi_thumb];
+            prop_ids.extend(colr_color_prop);
+            prop_ids.extend(icc_color_prop);
+            prop_ids.extend_from_slice(&orientation_props);
+            ipma_entries.push(IpmaEntry { item_id: thumb_id, prop_ids });
+
+            let thumb_extent = IlocItem {
+                id: thumb_id,
+                extents: from_array([IlocExtent { data: &thumb.av1_data }]),
+            };
+
+            if let Some(alpha_data) = thumb.alpha_av1_data.as_deref() {
+                let alpha_thumb_id = next_item_id(&image_items)?;
+                // Thumbnails have their own size, so they're only rotated and mirrored
+                self.push_alpha_item(alpha_thumb_id, FourCC(*b"av01"), thumb_id, thumb.depth_bits, ispe_thumb, &orientation_props, &mut ipco, &mut image_items, &mut ipma_entries, &mut irefs)?;
+
+                // Alpha first, like the image
+                iloc_items.push(IlocItem {
+                    id: alpha_thumb_id,
+                    extents: from_array([IlocExtent { data: alpha_data }]),
+                });
+            }
+            iloc_items.push(thumb_extent);
+        }
+
+        // After the image data, since it's not needed to display the image
+        if let Some(xmp_data) = self.xmp.as_deref() {
+            let xmp_id = next_item_id(&image_items)?;
+            image_items.push(InfeBox {
+                id: xmp_id,
+                typ: FourCC(*b"mime"),
+                name: "",
+                content_type: "application/rdf+xml",
+                hidden: false,
             });
 
-            // Use interleaved color and alpha, with alpha first.
-            // Makes it possible to display partial image.
             iloc_items.push(IlocItem {
-                id: alpha_image_id,
-                extents: from_array([IlocExtent { data: alpha_data }]),
+                id: xmp_id,
+                extents: from_array([IlocExtent { data: xmp_data }]),
+            });
+
+            irefs.push(IrefEntryBox {
+                from_id: xmp_id,
+                to_ids: vec![color_image_id],
+                typ: FourCC(*b"cdsc"),
+            });
+        }
+
+        // Group IDs share the number space with item IDs
+        let mut groups = Vec::new();
+        if !altr_entities.is_empty() {
+            altr_entities.push(u32::from(color_image_id));
+            groups.push(EntityToGroupBox {
+                typ: FourCC(*b"altr"),
+                group_id: u32::from(next_item_id(&image_items)?),
+                entity_ids: altr_entities,
             });
         }
-        iloc_items.push(IlocItem {
-            id: color_image_id,
-            extents: from_array([IlocExtent { data: color_av1_data }]),
-        });
+
+        // Items are added out of order (e.g. alpha grid after color tiles), but ipma must be ordered by item ID
+        ipma_entries.sort_by_key(|e| e.item_id);
+
+        let mut compatible_brands = from_array([FourCC(*b"mif1"), FourCC(*b"miaf")]);
+        // Layer selection is from the 2nd edition of HEIF
+        if layered {
+            compatible_brands.push(FourCC(*b"mif2"));
+        }
+        if self.gain_map.is_some() {
+            compatible_brands.push(FourCC(*b"tmap"));
+        }
 
         Ok(AvifFile {
             ftyp: FtypBox {
                 major_brand: FourCC(*b"avif"),
                 minor_version: 0,
-                compatible_brands: [FourCC(*b"mif1"), FourCC(*b"miaf")].into(),
+                compatible_brands,
             },
             meta: MetaBox {
-                hdlr: HdlrBox {},
+                hdlr: HdlrBox { handler_type: FourCC(*b"pict") },
                 iinf: IinfBox { items: image_items },
                 pitm: PitmBox(color_image_id),
                 iloc: IlocBox {
                     absolute_offset_start: None,
                     items: iloc_items,
+                    idat_items,
                 },
                 iprp: IprpBox {
                     ipco,
@@ -365,6 +1656,7 @@ impl Aviffy {
                     ipma: IpmaBox { entries: ipma_entries },
                 },
                 iref: IrefBox { entries: irefs },
+                grpl: GrplBox { groups },
             },
             // Here's the actual data. If HEIF wasn't such a kitchen sink, this
             // would have been the only data this file needs.
@@ -372,41 +1664,465 @@ impl Aviffy {
         })
     }
 
+    /// Adds an alpha auxiliary item for the image `color_id`: its `infe`, `auxl` (and `prem`) references, and properties followed by `transform_props`.
+    /// The alpha's data (`iloc` item) is up to the caller, because it's interleaved differently for each kind of image.
+    ///
+    /// Returns `av1C`, `auxC`, and `pixi` properties, which tiles of an alpha grid need too.
+    #[allow(clippy::too_many_arguments)]
+    fn push_alpha_item(&self, alpha_id: u16, typ: FourCC, color_id: u16, depth_bits: u8, ispe_prop: u8, transform_props: &[u8], ipco: &mut IpcoBox, image_items: &mut Vec<InfeBox>, ipma_entries: &mut Vec<IpmaEntry>, irefs: &mut Vec<IrefEntryBox>) -> io::Result<[u8; 3]> {
+        image_items.push(InfeBox {
+            id: alpha_id,
+            typ,
+            name: "",
+            content_type: "",
+            hidden: false,
+        });
+
+        irefs.push(IrefEntryBox {
+            from_id: alpha_id,
+            to_ids: vec![color_id],
+            typ: FourCC(*b"auxl"),
+        });
+
+        if self.premultiplied_alpha {
+            irefs.push(IrefEntryBox {
+                from_id: color_id,
+                to_ids: vec![alpha_id],
+                typ: FourCC(*b"prem"),
+            });
+        }
+
+        let av1c_alpha_prop = ipco.push(boxes::IpcoProp::Av1C(alpha_av1c(depth_bits))).ok_or(io::ErrorKind::InvalidInput)?;
+
+        // So pointless
+        let pixi_1 = ipco.push(IpcoProp::Pixi(PixiBox {
+            channels: 1,
+            depth: depth_bits,
+        })).ok_or(io::ErrorKind::InvalidInput)?;
+
+        // that's a silly way to add 1 bit of information, isn't it?
+        let auxc_prop = ipco.push(IpcoProp::AuxC(AuxCBox {
+            urn: ALPHA_URN,
+            subtype: Vec::new(),
+        })).ok_or(io::ErrorKind::InvalidInput)?;
+
+        // Derived images aren't AV1 themselves, only their inputs are
+        let mut prop_ids = if typ.0 == *b"av01" {
+            vec![ispe_prop, av1c_alpha_prop | ESSENTIAL_BIT, auxc_prop, pixi_1]
+        } else {
+            vec![ispe_prop, pixi_1, auxc_prop]
+        };
+        // Alpha must be transformed the same way as the color it applies to
+        prop_ids.extend_from_slice(transform_props);
+        ipma_entries.push(IpmaEntry { item_id: alpha_id, prop_ids });
+
+        Ok([av1c_alpha_prop | ESSENTIAL_BIT, auxc_prop, pixi_1])
+    }
+
+    fn color_av1c(&self, depth_bits: u8) -> Av1CBox {
+        Av1CBox {
+            seq_profile: self.min_seq_profile.max(if depth_bits >= 12 { 2 } else { 0 }),
+            seq_level_idx_0: 31,
+            seq_tier_0: false,
+            high_bitdepth: depth_bits >= 10,
+            twelve_bit: depth_bits >= 12,
+            monochrome: self.monochrome,
+            chroma_subsampling_x: self.chroma_subsampling.0,
+            chroma_subsampling_y: self.chroma_subsampling.1,
+            chroma_sample_position: 0,
+        }
+    }
+
+    /// Makes an animated AVIF (AVIF image sequence) from already-encoded AV1 frames.
+    ///
+    /// Frame durations are in units of `timescale` per second, e.g. with `timescale` = 1000 durations are in milliseconds.
+    ///
+    /// Optional `alpha_frames` are monochrome frames representing transparency, the per-frame equivalent of `alpha_av1_data` in [`Self::write`].
+    /// They're written as a separate auxiliary track. There must be one alpha frame for every color frame, with the same duration.
+    ///
+    /// Width, height and bit depth must have been set with [`Self::set_width`], [`Self::set_height`], and [`Self::set_bit_depth`].
+    /// All frames must have the same dimensions and depth.
+    ///
+    /// Data is written (streamed) to `into_output`.
+    #[inline]
+    pub fn write_animation<W: io::Write>(&self, into_output: W, frames: &[AnimationFrame<'_>], alpha_frames: Option<&[AnimationFrame<'_>]>, timescale: u32) -> io::Result<()> {
+        self.make_sequence_boxes(None, frames, alpha_frames, timescale)?.write(into_output)
+    }
+
+    /// Panics if the input arguments were invalid. Use [`Self::write_animation`] to handle the errors.
+    #[must_use]
+    #[track_caller]
+    pub fn animation_to_vec(&self, frames: &[AnimationFrame<'_>], alpha_frames: Option<&[AnimationFrame<'_>]>, timescale: u32) -> Vec<u8> {
+        let mut file = self.make_sequence_boxes(None, frames, alpha_frames, timescale).unwrap();
+        let mut out = Vec::new();
+        file.write_to_vec(&mut out).unwrap();
+        out
+    }
+
+    /// Like [`Self::write_animation`], but the file also contains a still image,
+    /// which is what decoders that don't support animated AVIF will display.
+    ///
+    /// The still image gets all the same properties (color, Exif, etc.) as [`Self::write`] would write.
+    #[inline]
+    pub fn write_animation_with_poster<W: io::Write>(&self, into_output: W, poster: Poster<'_>, frames: &[AnimationFrame<'_>], alpha_frames: Option<&[AnimationFrame<'_>]>, timescale: u32) -> io::Result<()> {
+        self.make_sequence_boxes(Some(poster), frames, alpha_frames, timescale)?.write(into_output)
+    }
+
+    /// Panics if the input arguments were invalid. Use [`Self::write_animation_with_poster`] to handle the errors.
+    #[must_use]
+    #[track_caller]
+    pub fn animation_with_poster_to_vec(&self, poster: Poster<'_>, frames: &[AnimationFrame<'_>], alpha_frames: Option<&[AnimationFrame<'_>]>, timescale: u32) -> Vec<u8> {
+        let mut file = self.make_sequence_boxes(Some(poster), frames, alpha_frames, timescale).unwrap();
+        let mut out = Vec::new();
+        file.write_to_vec(&mut out).unwrap();
+        out
+    }
+
+    fn make_sequence_boxes<'data>(&'data self, poster: Option<Poster<'data>>, frames: &[AnimationFrame<'data>], alpha_frames: Option<&[AnimationFrame<'data>]>, timescale: u32) -> io::Result<AvisFile<'data>> {
+        if timescale == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "timescale must be > 0"));
+        }
+        if !frames.first().is_some_and(|f| f.is_keyframe) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the first frame must be a keyframe"));
+        }
+        if let Some(alpha_frames) = alpha_frames {
+            if alpha_frames.len() != frames.len() || alpha_frames.iter().zip(frames).any(|(a, c)| a.duration != c.duration) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha frames must match color frames"));
+            }
+            if !alpha_frames[0].is_keyframe {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "the first frame must be a keyframe"));
+            }
+        }
+
+        let duration = frames.iter().try_fold(0_u32, |sum, f| sum.checked_add(f.duration))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "animation too long"))?;
+
+        // The edit list covers the media once, and gets repeated until it fills the track duration
+        let (track_duration, edts) = match self.loop_count {
+            None => (duration, None),
+            Some(LoopCount::Infinite) => (u32::MAX, Some(EdtsBox { repeat: true, segment_duration: duration })),
+            Some(LoopCount::Times(times)) => (
+                duration.checked_mul(times.get()).filter(|&d| d != u32::MAX)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "animation too long"))?,
+                Some(EdtsBox { repeat: times.get() > 1, segment_duration: duration }),
+            ),
+        };
+
+        let tracks = self.make_tracks(frames, alpha_frames, timescale, duration, track_duration, edts)?;
+
+        // The first frame's samples are stored only once, as the poster's items
+        let first_frame_poster = matches!(poster, Some(Poster::FirstFrame));
+        let chunks = if let Some(alpha_frames) = alpha_frames {
+            // Interleaved like the still image, with alpha first
+            alpha_frames.iter().zip(frames).enumerate()
+                .flat_map(|(i, (a, c))| {
+                    let in_poster = first_frame_poster && i == 0;
+                    [MdatChunk { track: 1, data: a.data, in_poster }, MdatChunk { track: 0, data: c.data, in_poster }]
+                })
+                .collect()
+        } else {
+            frames.iter().enumerate().map(|(i, f)| MdatChunk { track: 0, data: f.data, in_poster: first_frame_poster && i == 0 }).collect()
+        };
+
+        let (meta, compatible_brands) = match poster {
+            None => (None, from_array([FourCC(*b"avis"), FourCC(*b"msf1"), FourCC(*b"iso8"), FourCC(*b"miaf")])),
+            Some(poster) => {
+                let (color_av1_data, alpha_av1_data) = match poster {
+                    // Frames have already been checked to be non-empty
+                    Poster::FirstFrame => (frames[0].data, alpha_frames.map(|a| a[0].data)),
+                    Poster::Image { color_av1_data, alpha_av1_data } => (color_av1_data, alpha_av1_data),
+                };
+                let still = self.make_boxes(ImageData::Coded(color_av1_data), alpha_av1_data.map(ImageData::Coded), self.width, self.height, self.bit_depth)?;
+                // Same brands as the still image, which declare its features (layers, gain maps, etc.)
+                let mut compatible_brands = from_array([FourCC(*b"avif")]);
+                compatible_brands.extend(still.ftyp.compatible_brands);
+                compatible_brands.extend([FourCC(*b"avis"), FourCC(*b"msf1"), FourCC(*b"iso8")]);
+                (Some(still.meta), compatible_brands)
+            },
+        };
+
+        Ok(AvisFile {
+            ftyp: FtypBox {
+                major_brand: FourCC(*b"avis"),
+                minor_version: 0,
+                compatible_brands,
+            },
+            meta,
+            moov: MoovBox {
+                mvhd: MvhdBox {
+                    timescale,
+                    duration: track_duration,
+                    next_track_id: tracks.len() as u32 + 1,
+                },
+                tracks,
+                mvex: None,
+            },
+            mdat: SequenceMdatBox { chunks },
+        })
+    }
+
+    /// Color track, and optionally an alpha track. Frames can be empty when they're going to be in fragments.
+    fn make_tracks(&self, frames: &[AnimationFrame<'_>], alpha_frames: Option<&[AnimationFrame<'_>]>, timescale: u32, duration: u32, track_duration: u32, edts: Option<EdtsBox>) -> io::Result<ArrayVec<TrakBox, 2>> {
+        if ![8, 10, 12].contains(&self.bit_depth) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "depth must be 8/10/12"));
+        }
+        let (Ok(width), Ok(height)) = (u16::try_from(self.width), u16::try_from(self.height)) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "width/height too large"));
+        };
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing width/height"));
+        }
+        if self.icc.as_ref().is_some_and(|icc| icc.icc_profile.get(36..40) != Some(b"acsp")) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid ICC profile"));
+        }
+
+        let color_track_id = 1;
+        let alpha_track_id = 2;
+        let tkhd = TkhdBox {
+            track_id: color_track_id,
+            duration: track_duration,
+            width: self.width,
+            height: self.height,
+        };
+
+        let mut tracks = ArrayVec::new();
+        tracks.push(TrakBox {
+            tkhd,
+            edts,
+            tref: if self.premultiplied_alpha && alpha_frames.is_some() {
+                Some(TrefBox { typ: FourCC(*b"prem"), track_id: alpha_track_id })
+            } else {
+                None
+            },
+            mdia: sequence_media(FourCC(*b"pict"), Av01SampleEntry {
+                width,
+                height,
+                av1c: self.color_av1c(self.bit_depth),
+                colr: Some(self.colr).filter(|colr| *colr != ColrBox::default()),
+                icc: self.icc.clone(),
+                clli: self.clli,
+                mdcv: self.mdcv,
+                cclv: self.cclv,
+                amve: self.amve,
+                auxi: None,
+            }, frames, timescale, duration)?,
+        });
+
+        if let Some(alpha_frames) = alpha_frames {
+            tracks.push(TrakBox {
+                tkhd: TkhdBox { track_id: alpha_track_id, ..tkhd },
+                edts,
+                tref: Some(TrefBox { typ: FourCC(*b"auxl"), track_id: color_track_id }),
+                mdia: sequence_media(FourCC(*b"auxv"), Av01SampleEntry {
+                    width,
+                    height,
+                    av1c: alpha_av1c(self.bit_depth),
+                    colr: None,
+                    icc: None,
+                    clli: None,
+                    mdcv: None,
+                    cclv: None,
+                    amve: None,
+                    auxi: Some(AuxiBox { urn: ALPHA_URN }),
+                }, alpha_frames, timescale, duration)?,
+            });
+        }
+        Ok(tracks)
+    }
+
+    /// Starts writing an animated AVIF in fragments, for animations that are too long to buffer, or are generated live.
+    ///
+    /// This immediately writes a header without any frames. Frames are then appended with [`FragmentedWriter::write_fragment`].
+    /// Set `has_alpha` if fragments are going to have alpha frames. Other arguments are the same as for [`Self::write_animation`].
+    ///
+    /// The edit list needs the total duration, which isn't known up front, so this fails if [`Self::set_loop_count`] has been used.
+    pub fn fragmented_writer<W: io::Write>(&self, mut into_output: W, has_alpha: bool, timescale: u32) -> io::Result<FragmentedWriter<W>> {
+        if timescale == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "timescale must be > 0"));
+        }
+        if self.loop_count.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "loop count can't be set for fragmented animations"));
+        }
+        let tracks = self.make_tracks(&[], has_alpha.then_some(&[]), timescale, 0, 0, None)?;
+        let ftyp = FtypBox {
+            major_brand: FourCC(*b"avis"),
+            minor_version: 0,
+            compatible_brands: from_array([FourCC(*b"avis"), FourCC(*b"msf1"), FourCC(*b"iso8"), FourCC(*b"miaf")]),
+        };
+        let moov = MoovBox {
+            mvhd: MvhdBox {
+                timescale,
+                duration: 0, // unknown
+                next_track_id: tracks.len() as u32 + 1,
+            },
+            mvex: Some(MvexBox { track_ids: tracks.iter().map(|t| t.tkhd.track_id).collect() }),
+            tracks,
+        };
+
+        let mut tmp = Vec::new();
+        tmp.try_reserve_exact(ftyp.len() + moov.len())?;
+        let mut w = Writer::new(&mut tmp);
+        ftyp.write(&mut w).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        moov.write(&mut w).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        drop(w);
+        into_output.write_all(&tmp)?;
+
+        Ok(FragmentedWriter {
+            out: into_output,
+            has_alpha,
+            sequence_number: 0,
+            decode_time: 0,
+        })
+    }
+
     /// Panics if the input arguments were invalid. Use [`Self::write`] to handle the errors.
     #[must_use]
     #[track_caller]
     pub fn to_vec(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Vec<u8> {
-        let mut file = self.make_boxes(color_av1_data, alpha_av1_data, width, height, depth_bits).unwrap();
+        let mut file = self.make_boxes(ImageData::Coded(color_av1_data), alpha_av1_data.map(ImageData::Coded), width, height, depth_bits).unwrap();
         let mut out = Vec::new();
         file.write_to_vec(&mut out).unwrap();
         out
     }
 
-    /// `(false, false)` is 4:4:4
-    /// `(true, true)` is 4:2:0
+    /// `(false, false)` is 4:4:4
+    /// `(true, true)` is 4:2:0
+    ///
+    /// `chroma_sample_position` is always 0. Don't use chroma subsampling with AVIF.
+    #[inline]
+    pub fn set_chroma_subsampling(&mut self, subsampled_xy: (bool, bool)) -> &mut Self {
+        self.chroma_subsampling = subsampled_xy;
+        self
+    }
+
+    /// Set whether the image is monochrome (grayscale).
+    /// This is used to set the `monochrome` flag in the AV1 sequence header.
+    #[inline]
+    pub fn set_monochrome(&mut self, monochrome: bool) -> &mut Self {
+        self.monochrome = monochrome;
+        self
+    }
+
+    /// Set Exif metadata to be included in the AVIF file as a separate item.
+    ///
+    /// A TIFF Exif block will be written in the AVIF/HEIF Exif item form.
+    /// Already-framed AVIF/HEIF Exif item data is preserved.
+    #[inline]
+    pub fn set_exif(&mut self, exif: Vec<u8>) -> &mut Self {
+        self.exif = Some(exif);
+        self
+    }
+
+    /// Set XMP metadata to be included in the AVIF file as a separate item.
+    ///
+    /// This should be a complete XMP packet (`<?xpacket begin=…`), in UTF-8.
+    #[inline]
+    pub fn set_xmp(&mut self, xmp: Vec<u8>) -> &mut Self {
+        self.xmp = Some(xmp);
+        self
+    }
+
+    /// Add an HDR gain map (ISO 21496-1), so that displays that support it can show the alternate rendition of the image.
+    ///
+    /// The image written by [`Self::write`] is the base image, and stays the primary item for decoders that don't support gain maps.
+    /// Adds a `tmap` derived item, the gain map item, and an `altr` group preferring the `tmap` item.
+    #[inline]
+    pub fn set_gain_map(&mut self, gain_map: GainMap) -> &mut Self {
+        self.gain_map = Some(gain_map);
+        self
+    }
+
+    /// Add an HDR gain map in Apple's format, which iOS and macOS use to display the image in HDR.
+    ///
+    /// `av1_data` is an already-encoded monochrome AV1 image of `width`×`height` (usually half the size of the image), with `depth_bits` 8, 10 or 12.
+    /// `headroom` is how much brighter than SDR white the HDR rendition gets, as a linear ratio (e.g. `4.` for 2 stops).
+    /// Values below 1 are treated as 1.
+    ///
+    /// Adds an auxiliary item of type `urn:com:apple:photo:2020:aux:hdrgainmap`, with XMP metadata for it.
+    /// For the standard ISO 21496-1 gain maps, see [`Self::set_gain_map`].
+    pub fn set_apple_gain_map(&mut self, av1_data: Vec<u8>, width: u32, height: u32, depth_bits: u8, headroom: f32) -> &mut Self {
+        let xmp = format!(r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?><x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:HDRGainMap="http://ns.apple.com/HDRGainMap/1.0/"><HDRGainMap:HDRGainMapVersion>131072</HDRGainMap:HDRGainMapVersion><HDRGainMap:HDRGainMapHeadroom>{}</HDRGainMap:HDRGainMapHeadroom></rdf:Description></rdf:RDF></x:xmpmeta><?xpacket end="w"?>"#, headroom.max(1.));
+        self.auxiliary_images.retain(|aux| aux.urn != APPLE_GAIN_MAP_URN);
+        self.auxiliary_images.push(AuxiliaryImage {
+            urn: APPLE_GAIN_MAP_URN,
+            av1_data,
+            width,
+            height,
+            depth_bits,
+            aux_subtype: Vec::new(),
+            xmp: Some(xmp.into_bytes()),
+        });
+        self
+    }
+
+    /// Add another rendition of the image, e.g. an HDR version of an SDR image, so that decoders can pick the best one they support.
+    /// Can be called multiple times, with the preferred renditions first.
+    ///
+    /// The image passed to [`Self::write`] stays the primary item for decoders that don't support alternatives,
+    /// and is the last choice for those that do, so it should be the most compatible one.
+    ///
+    /// Adds an `altr` entity group (`grpl` box) listing the renditions in order of preference.
+    /// Alternatives are listed before the `tmap` item of [`Self::set_gain_map`].
+    #[inline]
+    pub fn add_alternative(&mut self, alternative: Alternative) -> &mut Self {
+        self.alternatives.push(alternative);
+        self
+    }
+
+    /// Make the image progressive: its AV1 data has 2 to 4 layers (spatial or quality), which decoders can display one by one as they arrive.
+    ///
+    /// `layer_sizes` are byte lengths of the layers, in order, and must add up to the length of the color AV1 data.
+    /// They can be found with [`av1_layer_sizes`]. An empty slice makes the image non-progressive again.
+    ///
+    /// Adds `a1lx` (layer sizes) and `lsel` (allowing any layer to be displayed) properties to the primary item.
+    #[inline]
+    pub fn set_progressive_layers(&mut self, layer_sizes: &[u32]) -> &mut Self {
+        self.layer_sizes = layer_sizes.to_vec();
+        self
+    }
+
+    /// Select which operating point of a scalable AV1 bitstream decoders should use, as an index into the operating points of its sequence header.
+    /// Without it, decoders use operating point 0.
     ///
-    /// `chroma_sample_position` is always 0. Don't use chroma subsampling with AVIF.
+    /// The AV1 data of the image must have a sequence header with that many operating points.
+    ///
+    /// Adds an `a1op` property to the primary item.
     #[inline]
-    pub fn set_chroma_subsampling(&mut self, subsampled_xy: (bool, bool)) -> &mut Self {
-        self.chroma_subsampling = subsampled_xy;
+    pub fn set_operating_point(&mut self, op_index: Option<u8>) -> &mut Self {
+        self.a1op = op_index.map(|op_index| A1opBox { op_index });
         self
     }
 
-    /// Set whether the image is monochrome (grayscale).
-    /// This is used to set the `monochrome` flag in the AV1 sequence header.
-    #[inline]
-    pub fn set_monochrome(&mut self, monochrome: bool) -> &mut Self {
-        self.monochrome = monochrome;
+    /// Add a small preview of the image, so that image browsers don't need to decode the whole image.
+    /// Can be called multiple times to add thumbnails of different sizes.
+    ///
+    /// `av1_data` is an already-encoded AV1 image of `width`×`height`, with `depth_bits` 8, 10 or 12.
+    /// It must have the same chroma subsampling and color space as the image.
+    /// Optional `alpha_av1_data` is a monochrome image of the same size, representing transparency of the thumbnail.
+    ///
+    /// Adds an item with a `thmb` reference to the primary image. Thumbnails are rotated and mirrored like the image, but not cropped or scaled.
+    pub fn add_thumbnail(&mut self, av1_data: Vec<u8>, alpha_av1_data: Option<Vec<u8>>, width: u32, height: u32, depth_bits: u8) -> &mut Self {
+        self.thumbnails.push(Thumbnail { av1_data, alpha_av1_data, width, height, depth_bits });
         self
     }
 
-    /// Set Exif metadata to be included in the AVIF file as a separate item.
+    /// Add a depth map, e.g. for 3D photos or portrait mode effects.
     ///
-    /// A TIFF Exif block will be written in the AVIF/HEIF Exif item form.
-    /// Already-framed AVIF/HEIF Exif item data is preserved.
-    #[inline]
-    pub fn set_exif(&mut self, exif: Vec<u8>) -> &mut Self {
-        self.exif = Some(exif);
+    /// `av1_data` is an already-encoded monochrome AV1 image of `width`×`height`, with `depth_bits` 8, 10 or 12.
+    /// Optional `representation` tells how the values translate to distances.
+    ///
+    /// Adds an auxiliary item of type `urn:mpeg:mpegB:cicp:systems:auxiliary:depth`, referencing the primary image with `auxl`.
+    pub fn set_depth_map(&mut self, av1_data: Vec<u8>, width: u32, height: u32, depth_bits: u8, representation: Option<&DepthRepresentation>) -> &mut Self {
+        self.auxiliary_images.retain(|aux| aux.urn != DEPTH_URN);
+        self.auxiliary_images.push(AuxiliaryImage {
+            urn: DEPTH_URN,
+            av1_data,
+            width,
+            height,
+            depth_bits,
+            aux_subtype: representation.map(DepthRepresentation::aux_subtype).unwrap_or_default(),
+            xmp: None,
+        });
         self
     }
 
@@ -456,12 +2172,105 @@ impl Aviffy {
         self
     }
 
+    /// Set how many times an animation made with [`Self::write_animation`] should be played. Not supported by [`Self::fragmented_writer`].
+    ///
+    /// This is written as an edit list that repeats the whole animation.
+    /// If not set, there's no edit list, and it's up to decoders whether the animation loops (they usually do).
+    #[inline]
+    pub fn set_loop_count(&mut self, loop_count: LoopCount) -> &mut Self {
+        self.loop_count = Some(loop_count);
+        self
+    }
+
     #[doc(hidden)]
     pub fn premultiplied_alpha(&mut self, is_premultiplied: bool) -> &mut Self {
         self.set_premultiplied_alpha(is_premultiplied)
     }
 }
 
+impl Default for Aviffy {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Media part of a track with one sample per frame
+fn sequence_media(handler_type: FourCC, av01: Av01SampleEntry, frames: &[AnimationFrame<'_>], timescale: u32, duration: u32) -> io::Result<MdiaBox> {
+    let sample_sizes = frames.iter().map(|f| u32::try_from(f.data.len()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    // Listing keyframes is unnecessary if they're all keyframes
+    let keyframes = if frames.iter().all(|f| f.is_keyframe) { Vec::new() } else {
+        (1..).zip(frames).filter(|(_, f)| f.is_keyframe).map(|(n, _)| n).collect()
+    };
+
+    Ok(MdiaBox {
+        mdhd: MdhdBox { timescale, duration },
+        hdlr: HdlrBox { handler_type },
+        minf: MinfBox {
+            stbl: StblBox {
+                stsd: StsdBox { av01 },
+                stts: SttsBox::from_durations(frames.iter().map(|f| f.duration)),
+                stsc: StscBox { has_samples: !frames.is_empty() },
+                stco: StcoBox { offsets: vec![0; frames.len()] },
+                stsz: StszBox { sample_sizes },
+                stss: StssBox { sample_numbers: keyframes },
+            },
+        },
+    })
+}
+
+/// Adds hidden `av01` items for inputs of a derived image (tiles of a grid or layers of an overlay),
+/// referenced by the derived item with `dimg`. Input items get consecutive ids.
+fn derived_input_items<'data>(derived_id: u16, first_id: u16, inputs: impl ExactSizeIterator<Item = (&'data [u8], Vec<u8>)>, image_items: &mut Vec<InfeBox>, ipma_entries: &mut Vec<IpmaEntry>, irefs: &mut Vec<IrefEntryBox>) -> io::Result<Vec<IlocItem<'data>>> {
+    u16::try_from(inputs.len()).ok().and_then(|n| first_id.checked_add(n))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many tiles or layers"))?;
+    let mut ids = Vec::with_capacity(inputs.len());
+    let mut iloc_items = Vec::with_capacity(inputs.len());
+    for (id, (data, prop_ids)) in (first_id..).zip(inputs) {
+        image_items.push(InfeBox {
+            id,
+            typ: FourCC(*b"av01"),
+            name: "",
+            content_type: "",
+            hidden: true,
+        });
+        ipma_entries.push(IpmaEntry { item_id: id, prop_ids });
+        iloc_items.push(IlocItem {
+            id,
+            extents: from_array([IlocExtent { data }]),
+        });
+        ids.push(id);
+    }
+    irefs.push(IrefEntryBox {
+        from_id: derived_id,
+        to_ids: ids,
+        typ: FourCC(*b"dimg"),
+    });
+    Ok(iloc_items)
+}
+
+/// For items added after the fixed ids and tiles
+fn next_item_id(image_items: &[InfeBox]) -> io::Result<u16> {
+    image_items.iter().map(|i| i.id).max().unwrap_or(0).checked_add(1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many items"))
+}
+
+fn alpha_av1c(depth_bits: u8) -> Av1CBox {
+    Av1CBox {
+        seq_profile: if depth_bits >= 12 { 2 } else { 0 },
+        seq_level_idx_0: 31,
+        seq_tier_0: false,
+        high_bitdepth: depth_bits >= 10,
+        twelve_bit: depth_bits >= 12,
+        monochrome: true,
+        chroma_subsampling_x: true,
+        chroma_subsampling_y: true,
+        chroma_sample_position: 0,
+    }
+}
+
 fn exif_extents(exif: &[u8]) -> ArrayVec<IlocExtent<'_>, 2> {
     if looks_like_heif_exif_item(exif) {
         return from_array([IlocExtent { data: exif }]);
@@ -484,7 +2293,7 @@ fn looks_like_heif_exif_item(exif: &[u8]) -> bool {
         return false;
     };
 
-    exif.get(tiff_start..).map_or(false, looks_like_tiff_header)
+    exif.get(tiff_start..).is_some_and(looks_like_tiff_header)
 }
 
 fn looks_like_tiff_header(data: &[u8]) -> bool {
@@ -727,6 +2536,1037 @@ fn no_hdr_metadata_by_default() {
     assert!(parser.mastering_display.is_none());
 }
 
+#[test]
+fn animation_roundtrip_parse_mp4() {
+    let frames = [
+        AnimationFrame { data: b"key1", duration: 100, is_keyframe: true },
+        AnimationFrame { data: b"delta12", duration: 100, is_keyframe: false },
+        AnimationFrame { data: b"key123", duration: 250, is_keyframe: true },
+    ];
+    let mut icc = vec![0; 132];
+    icc[36..40].copy_from_slice(b"acsp");
+    let avis = Aviffy::new().set_width(10).set_height(20).set_bit_depth(8).set_icc_profile(icc.clone()).animation_to_vec(&frames, None, 1000);
+
+    let ctx = mp4parse::read_mp4(&mut avis.as_slice()).unwrap();
+    assert_eq!(1, ctx.tracks.len());
+    let track = &ctx.tracks[0];
+    assert_eq!(1000, track.timescale.unwrap().0);
+    assert_eq!(450, track.duration.unwrap().0);
+    match &track.stsd.as_ref().unwrap().descriptions[0] {
+        mp4parse::SampleEntry::Video(v) => {
+            assert_eq!(mp4parse::CodecType::AV1, v.codec_type);
+            assert_eq!((10, 20), (v.width, v.height));
+        },
+        _ => panic!("not video"),
+    }
+
+    let samples = mp4parse::unstable::create_sample_table(track, 0.into()).unwrap();
+    assert_eq!(frames.len(), samples.len());
+    for (frame, sample) in frames.iter().zip(samples.iter()) {
+        let range = sample.start_offset.0 as usize..sample.end_offset.0 as usize;
+        assert_eq!(frame.data, &avis[range]);
+        assert_eq!(frame.is_keyframe, sample.sync);
+    }
+    assert_eq!(200, samples[2].start_composition.0);
+
+    let av01 = avis.windows(4).position(|w| w == b"av01").unwrap() - 4;
+    let av01 = &avis[av01..av01 + u32::from_be_bytes(avis[av01..av01 + 4].try_into().unwrap()) as usize];
+    let colr = [&(12 + icc.len() as u32).to_be_bytes()[..], b"colrprof", &icc].concat();
+    assert!(av01.windows(colr.len()).any(|w| w == colr));
+
+    assert!(Aviffy::new().set_width(10).set_height(20).set_bit_depth(8).set_icc_profile(vec![1, 2, 3]).write_animation(&mut vec![], &frames, None, 1000).is_err());
+}
+
+#[test]
+fn animation_alpha_track() {
+    let frames = [
+        AnimationFrame { data: b"color1", duration: 3, is_keyframe: true },
+        AnimationFrame { data: b"color22", duration: 4, is_keyframe: true },
+    ];
+    let alpha_frames = [
+        AnimationFrame { data: b"a1", duration: 3, is_keyframe: true },
+        AnimationFrame { data: b"a22", duration: 4, is_keyframe: false },
+    ];
+    let avis = Aviffy::new().set_width(10).set_height(20).set_bit_depth(10)
+        .set_premultiplied_alpha(true)
+        .animation_to_vec(&frames, Some(&alpha_frames), 10);
+
+    let ctx = mp4parse::read_mp4(&mut avis.as_slice()).unwrap();
+    assert_eq!(2, ctx.tracks.len());
+    let (color, alpha) = (&ctx.tracks[0], &ctx.tracks[1]);
+    assert_eq!(mp4parse::TrackType::Picture, color.track_type);
+    assert_eq!(mp4parse::TrackType::AuxiliaryVideo, alpha.track_type);
+    assert!(alpha.tref.as_ref().unwrap().has_auxl_reference(color.track_id.unwrap()));
+
+    for (track, frames) in [(color, &frames), (alpha, &alpha_frames)] {
+        let samples = mp4parse::unstable::create_sample_table(track, 0.into()).unwrap();
+        for (frame, sample) in frames.iter().zip(samples.iter()) {
+            assert_eq!(frame.data, &avis[sample.start_offset.0 as usize..sample.end_offset.0 as usize]);
+            assert_eq!(frame.is_keyframe, sample.sync);
+        }
+    }
+
+    assert!(avis.windows(ALPHA_URN.len()).any(|w| w == ALPHA_URN.as_bytes()));
+    assert!(avis.windows(8).any(|w| w == b"\0\0\0\x0cprem"));
+}
+
+#[test]
+fn animation_alpha_must_match() {
+    let frames = [AnimationFrame { data: b"color", duration: 3, is_keyframe: true }];
+    let alpha_frames = [AnimationFrame { data: b"alpha", duration: 4, is_keyframe: true }];
+    assert!(Aviffy::new().set_width(1).set_height(1).set_bit_depth(8).write_animation(&mut vec![], &frames, Some(&alpha_frames), 1).is_err());
+    assert!(Aviffy::new().set_width(1).set_height(1).set_bit_depth(8).write_animation(&mut vec![], &frames, Some(&[]), 1).is_err());
+}
+
+#[test]
+fn animation_loop_count() {
+    let frames = [
+        AnimationFrame { data: b"key1", duration: 10, is_keyframe: true },
+        AnimationFrame { data: b"key2", duration: 20, is_keyframe: true },
+    ];
+    let alpha_frames = frames;
+    let mut aviffy = Aviffy::new();
+    aviffy.set_width(1).set_height(1).set_bit_depth(8);
+
+    let avis = aviffy.animation_to_vec(&frames, None, 1000);
+    let ctx = mp4parse::read_mp4(&mut avis.as_slice()).unwrap();
+    assert_eq!(None, ctx.tracks[0].looped);
+
+    let avis = aviffy.set_loop_count(LoopCount::Infinite).animation_to_vec(&frames, Some(&alpha_frames), 1000);
+    let ctx = mp4parse::read_mp4(&mut avis.as_slice()).unwrap();
+    for track in ctx.tracks.iter() {
+        assert_eq!(Some(true), track.looped);
+        assert_eq!(30, track.edited_duration.unwrap().0);
+        assert_eq!(u64::from(u32::MAX), track.tkhd.as_ref().unwrap().duration);
+        assert_eq!(30, track.duration.unwrap().0);
+    }
+
+    let avis = aviffy.set_loop_count(LoopCount::Times(NonZeroU32::new(3).unwrap())).animation_to_vec(&frames, None, 1000);
+    let ctx = mp4parse::read_mp4(&mut avis.as_slice()).unwrap();
+    assert_eq!(Some(true), ctx.tracks[0].looped);
+    assert_eq!(90, ctx.tracks[0].tkhd.as_ref().unwrap().duration);
+
+    let avis = aviffy.set_loop_count(LoopCount::Times(NonZeroU32::new(1).unwrap())).animation_to_vec(&frames, None, 1000);
+    let ctx = mp4parse::read_mp4(&mut avis.as_slice()).unwrap();
+    assert_eq!(Some(false), ctx.tracks[0].looped);
+    assert_eq!(30, ctx.tracks[0].tkhd.as_ref().unwrap().duration);
+}
+
+#[test]
+fn animation_with_first_frame_poster() {
+    let frames = [
+        AnimationFrame { data: b"firstframe", duration: 1, is_keyframe: true },
+        AnimationFrame { data: b"second", duration: 1, is_keyframe: false },
+    ];
+    let alpha_frames = [
+        AnimationFrame { data: b"firstalpha", duration: 1, is_keyframe: true },
+        AnimationFrame { data: b"alpha2", duration: 1, is_keyframe: true },
+    ];
+    let avis = Aviffy::new().set_width(10).set_height(20).set_bit_depth(8)
+        .animation_with_poster_to_vec(Poster::FirstFrame, &frames, Some(&alpha_frames), 1);
+
+    let ctx = mp4parse::read_avif(&mut avis.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"avis", &ctx.major_brand.value);
+    assert_eq!(frames[0].data, ctx.primary_item_coded_data().unwrap());
+    assert_eq!(alpha_frames[0].data, ctx.alpha_item_coded_data().unwrap());
+
+    let seq = ctx.sequence.as_ref().unwrap();
+    for (track, frames) in seq.tracks.iter().zip([&frames, &alpha_frames]) {
+        let samples = mp4parse::unstable::create_sample_table(track, 0.into()).unwrap();
+        for (frame, sample) in frames.iter().zip(samples.iter()) {
+            assert_eq!(frame.data, &avis[sample.start_offset.0 as usize..sample.end_offset.0 as usize]);
+        }
+    }
+
+    // Not duplicated
+    assert_eq!(1, avis.windows(10).filter(|&w| w == b"firstframe").count());
+    assert_eq!(1, avis.windows(10).filter(|&w| w == b"firstalpha").count());
+
+    let ftyp = &test_child_boxes(&avis)[0];
+    assert_eq!(b"avis\0\0\0\0avifmif1miafavismsf1iso8", ftyp.2);
+
+    // The poster's brands come from the still image
+    let av1 = [0x0A, 3, 0, 0, 0, 0x32, 0, 0x32, 0];
+    let frames = [AnimationFrame { data: &av1, duration: 1, is_keyframe: true }];
+    let avis = Aviffy::new().set_width(10).set_height(20).set_bit_depth(8).set_progressive_layers(&[7, 2])
+        .animation_with_poster_to_vec(Poster::FirstFrame, &frames, None, 1);
+    let ftyp = &test_child_boxes(&avis)[0];
+    assert_eq!(b"avis\0\0\0\0avifmif1miafmif2avismsf1iso8", ftyp.2);
+}
+
+#[test]
+fn animation_with_separate_poster() {
+    let frames = [AnimationFrame { data: b"frame", duration: 1, is_keyframe: true }];
+    let poster = Poster::Image { color_av1_data: b"poster", alpha_av1_data: None };
+    let avis = Aviffy::new().set_width(10).set_height(20).set_bit_depth(8)
+        .animation_with_poster_to_vec(poster, &frames, None, 1);
+
+    let ctx = mp4parse::read_avif(&mut avis.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"poster", ctx.primary_item_coded_data().unwrap());
+    let track = &ctx.sequence.as_ref().unwrap().tracks[0];
+    let samples = mp4parse::unstable::create_sample_table(track, 0.into()).unwrap();
+    assert_eq!(b"frame", &avis[samples[0].start_offset.0 as usize..samples[0].end_offset.0 as usize]);
+
+    // A copy of the first frame is a separate image, and it's not mistaken for the first frame's sample
+    let copy = frames[0].data.to_vec();
+    let poster = Poster::Image { color_av1_data: &copy, alpha_av1_data: None };
+    let avis = Aviffy::new().set_width(10).set_height(20).set_bit_depth(8)
+        .animation_with_poster_to_vec(poster, &frames, None, 1);
+    let ctx = mp4parse::read_avif(&mut avis.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"frame", ctx.primary_item_coded_data().unwrap());
+    let track = &ctx.sequence.as_ref().unwrap().tracks[0];
+    let samples = mp4parse::unstable::create_sample_table(track, 0.into()).unwrap();
+    assert_eq!(b"frame", &avis[samples[0].start_offset.0 as usize..samples[0].end_offset.0 as usize]);
+    assert_eq!(2, avis.windows(5).filter(|&w| w == b"frame").count());
+}
+
+#[test]
+fn fragmented_animation() {
+    let fragments = [
+        [AnimationFrame { data: b"key1", duration: 2, is_keyframe: true }, AnimationFrame { data: b"delta1", duration: 3, is_keyframe: false }],
+        [AnimationFrame { data: b"key2", duration: 5, is_keyframe: true }, AnimationFrame { data: b"delta2", duration: 7, is_keyframe: false }],
+    ];
+    let alpha = |f: &AnimationFrame<'_>| AnimationFrame { data: if f.is_keyframe { b"akey" } else { b"adelta" }, ..*f };
+
+    let mut writer = Aviffy::new().set_width(1).set_height(1).set_bit_depth(8).fragmented_writer(Vec::new(), true, 100).unwrap();
+    assert!(writer.write_fragment(&fragments[0], None).is_err());
+    for frames in &fragments {
+        let alpha_frames = frames.each_ref().map(alpha);
+        writer.write_fragment(frames, Some(&alpha_frames)).unwrap();
+    }
+    let out = writer.into_inner();
+
+    let ctx = mp4parse::read_mp4(&mut out.as_slice()).unwrap();
+    assert_eq!(2, ctx.tracks.len());
+
+    let top = test_child_boxes(&out);
+    assert_eq!(["ftyp", "moov", "moof", "mdat", "moof", "mdat"], top.iter().map(|b| b.0).collect::<Vec<_>>().as_slice());
+    assert!(test_child_boxes(top[1].2).iter().any(|b| b.0 == "mvex"));
+
+    let mut decode_time = 0;
+    for ((_, moof_start, moof), frames) in top.iter().filter(|b| b.0 == "moof").zip(&fragments) {
+        for (traf, frames) in test_child_boxes(moof).iter().filter(|b| b.0 == "traf").zip([*frames, frames.each_ref().map(alpha)]) {
+            let traf = test_child_boxes(traf.2);
+            let tfdt = traf.iter().find(|b| b.0 == "tfdt").unwrap().2;
+            assert_eq!(decode_time, u64::from_be_bytes(tfdt[4..12].try_into().unwrap()));
+            let trun = traf.iter().find(|b| b.0 == "trun").unwrap().2;
+            let read_u32 = |pos: usize| u32::from_be_bytes(trun[pos..pos + 4].try_into().unwrap());
+            assert_eq!(2, read_u32(4));
+            let mut sample_start = moof_start + read_u32(8) as usize;
+            for (i, frame) in frames.iter().enumerate() {
+                assert_eq!(frame.duration, read_u32(12 + i * 12));
+                let size = read_u32(16 + i * 12) as usize;
+                assert_eq!(frame.data, &out[sample_start..sample_start + size]);
+                sample_start += size;
+            }
+        }
+        decode_time += frames.iter().map(|f| u64::from(f.duration)).sum::<u64>();
+    }
+
+    let mut writer = Aviffy::new().set_width(1).set_height(1).set_bit_depth(8).fragmented_writer(Vec::new(), false, 100).unwrap();
+    writer.write_fragment(&fragments[0], None).unwrap();
+    assert!(writer.write_fragment(&fragments[1][1..], None).is_err());
+
+    assert!(Aviffy::new().set_width(1).set_height(1).set_bit_depth(8).set_loop_count(LoopCount::Infinite).fragmented_writer(Vec::new(), false, 100).is_err());
+}
+
+#[test]
+fn animation_requires_keyframe_first() {
+    let frames = [AnimationFrame { data: b"delta", duration: 1, is_keyframe: false }];
+    assert!(Aviffy::new().set_width(1).set_height(1).set_bit_depth(8).write_animation(&mut vec![], &frames, None, 1).is_err());
+    assert!(Aviffy::new().set_width(1).set_height(1).set_bit_depth(8).write_animation(&mut vec![], &[], None, 1).is_err());
+}
+
+#[test]
+fn grid_layout_plan() {
+    let layout = GridLayout::plan(10000, 3000, 4096, 4096, (false, false)).unwrap();
+    assert_eq!(GridLayout { rows: 1, columns: 3, tile_width: 3334, tile_height: 3000 }, layout);
+
+    let layout = GridLayout::plan(8193, 4001, 4096, 2304, (true, true)).unwrap();
+    assert_eq!(GridLayout { rows: 2, columns: 3, tile_width: 2732, tile_height: 2002 }, layout);
+
+    let layout = GridLayout::plan(100, 100, 101, 101, (true, true)).unwrap();
+    assert_eq!(GridLayout { rows: 1, columns: 1, tile_width: 100, tile_height: 100 }, layout);
+
+    assert!(GridLayout::plan(100_000, 1, 100, 100, (false, false)).is_err());
+    assert!(GridLayout::plan(100, 100, 1, 100, (true, true)).is_err());
+}
+
+#[test]
+fn grid_roundtrip() {
+    let tiles: [&[u8]; 6] = [b"tile0", b"tile01", b"tile012", b"tile0123", b"tile01234", b"tile012345"];
+    let layout = GridLayout { rows: 2, columns: 3, tile_width: 4096, tile_height: 2048 };
+    let mut aviffy = Aviffy::new();
+    aviffy.set_width(12000).set_height(4000).set_bit_depth(10);
+    assert!(aviffy.write_grid(&mut vec![], &layout, &tiles[..5], None).is_err());
+    assert!(aviffy.write_grid(&mut vec![], &GridLayout { tile_width: 2048, ..layout }, &tiles, None).is_err());
+    let avif = aviffy.grid_to_vec(&layout, &tiles, None);
+
+    // Parsers don't support grids, but can check everything else
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert!(ctx.unsupported_features.contains(mp4parse::Feature::Grid));
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+    assert_eq!([0, 0, 0, 0, 0, 1], child("pitm"));
+    assert_eq!([0, 0, 1, 2, 0x2e, 0xe0, 0x0f, 0xa0], child("idat"));
+
+    let iinf = test_child_boxes(&child("iinf")[6..]);
+    assert_eq!(7, iinf.len());
+    assert_eq!(b"\x02\0\0\0\0\x01\0\0grid\0", iinf[0].2);
+    for infe in &iinf[1..] {
+        assert_eq!(b"\x02\0\0\x01", &infe.2[..4]);
+    }
+
+    let iref = test_child_boxes(&child("iref")[4..]);
+    assert_eq!("dimg", iref[0].0);
+    assert_eq!(b"\0\x01\0\x06\0\x04\0\x05\0\x06\0\x07\0\x08\0\x09", iref[0].2);
+
+    // version 1 iloc, with tiles in mdat first, and the grid in idat
+    let iloc = child("iloc");
+    assert_eq!(1, iloc[0]);
+    assert_eq!(7, u16::from_be_bytes([iloc[6], iloc[7]]));
+    for (i, tile) in tiles.iter().enumerate() {
+        let item = &iloc[8 + i * 16..][..16];
+        assert_eq!(i + 4, usize::from(u16::from_be_bytes([item[0], item[1]])));
+        assert_eq!([0, 0, 0, 0, 0, 1], item[2..8]);
+        let offset = u32::from_be_bytes(item[8..12].try_into().unwrap()) as usize;
+        let len = u32::from_be_bytes(item[12..16].try_into().unwrap()) as usize;
+        assert_eq!(*tile, &avif[offset..offset + len]);
+    }
+    assert_eq!([0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 8], iloc[8 + 6 * 16..]);
+
+    let iprp = test_child_boxes(child("iprp"));
+    assert_eq!([1, 4, 5, 6, 7, 8, 9], test_ipma_item_ids(iprp[1].2).as_slice());
+}
+
+#[test]
+fn alpha_grid() {
+    let tiles: [&[u8]; 2] = [b"color0", b"color1"];
+    let alpha_tiles: [&[u8]; 2] = [b"alpha0", b"alpha1"];
+    let layout = GridLayout { rows: 1, columns: 2, tile_width: 64, tile_height: 64 };
+    let mut aviffy = Aviffy::new();
+    aviffy.set_width(100).set_height(64).set_bit_depth(8).set_premultiplied_alpha(true);
+    assert!(aviffy.write_grid(&mut vec![], &layout, &tiles, Some(&alpha_tiles[..1])).is_err());
+    let avif = aviffy.grid_to_vec(&layout, &tiles, Some(&alpha_tiles));
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+
+    let iinf = test_child_boxes(&child("iinf")[6..]);
+    let grid_ids = iinf.iter().filter(|b| &b.2[8..12] == b"grid").map(|b| u16::from_be_bytes([b.2[4], b.2[5]])).collect::<Vec<_>>();
+    assert_eq!([1, 2], grid_ids.as_slice());
+
+    let iref = test_child_boxes(&child("iref")[4..]);
+    let refs = iref.iter().map(|b| (b.0, b.2)).collect::<Vec<_>>();
+    assert!(refs.contains(&("dimg", b"\0\x01\0\x02\0\x04\0\x05")));
+    assert!(refs.contains(&("dimg", b"\0\x02\0\x02\0\x06\0\x07")));
+    assert!(refs.contains(&("auxl", b"\0\x02\0\x01\0\x01")));
+    assert!(refs.contains(&("prem", b"\0\x01\0\x01\0\x02")));
+
+    // Alpha tiles interleaved before their color tiles
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert_eq!(b"alpha0color0alpha1color1", mdat);
+
+    // The alpha grid is created after color tiles, but ipma is ordered by item ID
+    let iprp = test_child_boxes(child("iprp"));
+    assert_eq!([1, 2, 4, 5, 6, 7], test_ipma_item_ids(iprp[1].2).as_slice());
+}
+
+#[test]
+fn overlay() {
+    let mut overlay = Overlay::new();
+    overlay.set_canvas_fill_color([0xFFFF, 0, 0x8000, 0xFFFF])
+        .add_layer(b"background", 640, 480, 0, 0)
+        .add_layer(b"logo", 64, 32, -10, 400);
+    let mut aviffy = Aviffy::new();
+    aviffy.set_width(640).set_height(480).set_bit_depth(8);
+    assert!(aviffy.write_overlay(&mut vec![], &Overlay::new()).is_err());
+    let avif = aviffy.overlay_to_vec(&overlay);
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+    assert_eq!(b"\0\0\xff\xff\0\0\x80\0\xff\xff\x02\x80\x01\xe0\0\0\0\0\xff\xf6\x01\x90", child("idat"));
+
+    let iref = test_child_boxes(&child("iref")[4..]);
+    assert_eq!(("dimg", &b"\0\x01\0\x02\0\x04\0\x05"[..]), (iref[0].0, iref[0].2));
+
+    // canvas and each layer size
+    let ipco = test_child_boxes(test_child_boxes(child("iprp"))[0].2);
+    let ispe = ipco.iter().filter(|b| b.0 == "ispe").map(|b| &b.2[4..]).collect::<Vec<_>>();
+    assert_eq!([b"\0\0\x02\x80\0\0\x01\xe0", b"\0\0\0\x40\0\0\0\x20"], ispe.as_slice());
+
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert_eq!(b"backgroundlogo", mdat);
+}
+
+#[test]
+fn rotation_and_mirror() {
+    let avif = Aviffy::new()
+        .set_rotation(Rotation::D270)
+        .set_mirror(Some(MirrorAxis::Vertical))
+        .to_vec(b"color", Some(b"alpha"), 10, 20, 8);
+
+    // Strict checks that the transformations are essential and in the right order
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert!(matches!(ctx.image_rotation().unwrap(), mp4parse::ImageRotation::D270));
+    assert!(!ctx.image_mirror_ptr().unwrap().is_null());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(b"color", ctx.primary_item.as_slice());
+
+    assert!(avif.windows(9).any(|w| w == b"\0\0\0\x09irot\x03"));
+    assert!(avif.windows(9).any(|w| w == b"\0\0\0\x09imir\0"));
+    // size before rotation
+    assert!(avif.windows(12).any(|w| w == b"ispe\0\0\0\0\0\0\0\x0a"));
+
+    let avif = Aviffy::new().set_rotation(Rotation::D0).set_mirror(None).to_vec(b"color", None, 10, 20, 8);
+    assert_eq!(serialize_to_vec(b"color", None, 10, 20, 8), avif);
+}
+
+#[test]
+fn crop_odd_size() {
+    let mut aviffy = Aviffy::new();
+    aviffy.set_chroma_subsampling((true, true)).set_rotation(Rotation::D90).set_crop(0, 0, 9, 19);
+    let avif = aviffy.to_vec(b"color", None, 10, 20, 8);
+
+    let clap_start = avif.windows(4).position(|w| w == b"clap").unwrap();
+    let clap = avif[clap_start + 4..][..32].chunks(4).map(|c| u32::from_be_bytes(c.try_into().unwrap()) as i32).collect::<Vec<_>>();
+    assert_eq!([9, 1, 19, 1, -1, 2, -1, 2], clap.as_slice());
+    // clap, then irot
+    assert!(clap_start < avif.windows(4).position(|w| w == b"irot").unwrap());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(b"color", ctx.primary_item.as_slice());
+
+    assert!(aviffy.set_crop(2, 2, 8, 18).write(&mut vec![], b"color", None, 10, 20, 8).is_ok());
+    assert!(aviffy.set_crop(2, 2, 9, 18).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    assert!(aviffy.set_crop(1, 0, 9, 20).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    assert!(aviffy.set_crop(0, 0, 0, 20).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn pixel_aspect_ratio_and_scaling() {
+    let mut aviffy = Aviffy::new();
+    aviffy.set_pixel_aspect_ratio(4, 3).set_scaling((2, 1), (3, 2));
+    let avif = aviffy.to_vec(b"color", None, 10, 20, 8);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(b"color", ctx.primary_item.as_slice());
+    // iscl is a transformation, so it's last and essential
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let iprp = test_child_boxes(meta.iter().find(|b| b.0 == "iprp").unwrap().2);
+    let iscl = test_child_boxes(iprp[0].2).iter().position(|b| b.0 == "iscl").unwrap();
+    assert!(iprp[1].2.ends_with(&[(iscl as u8 + 1) | 0x80]));
+
+    assert!(avif.windows(16).any(|w| w == b"\0\0\0\x10pasp\0\0\0\x04\0\0\0\x03"));
+    assert!(avif.windows(20).any(|w| w == b"\0\0\0\x14iscl\0\0\0\0\0\x02\0\x01\0\x03\0\x02"));
+
+    assert!(aviffy.set_scaling((1, 0), (1, 1)).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    let avif = Aviffy::new().set_pixel_aspect_ratio(4, 3).to_vec(b"color", None, 10, 20, 8);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"color", ctx.primary_item_coded_data().unwrap());
+    let avif = Aviffy::new().set_pixel_aspect_ratio(5, 5).to_vec(b"color", None, 10, 20, 8);
+    assert!(!avif.windows(4).any(|w| w == b"pasp"));
+    assert!(Aviffy::new().set_pixel_aspect_ratio(0, 0).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn content_color_volume_and_ambient_viewing_environment() {
+    let mut aviffy = Aviffy::new();
+    aviffy
+        .set_mastering_display([(8500, 39850), (6550, 2300), (35400, 14600)], (15635, 16450), 10_000_000, 50)
+        .set_content_color_volume(Some([(8500, 39850), (6550, 2300), (35400, 14600)]), Some(500), None, Some(1_000_000_000))
+        .set_ambient_viewing_environment(50000, (15635, 16450));
+    let avif = aviffy.to_vec(b"color", None, 10, 20, 10);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert!(ctx.mastering_display.is_some());
+
+    let mut cclv = b"\0\0\0\x29cclv\x34".to_vec();
+    for v in [8500, 39850, 6550, 2300, 35400, 14600, 500, 1_000_000_000_u32] {
+        cclv.extend_from_slice(&v.to_be_bytes());
+    }
+    let amve = b"\0\0\0\x10amve\0\0\xc3\x50\x3d\x13\x40\x42";
+    let mdcv_end = avif.windows(4).position(|w| w == b"mdcv").unwrap() + 4 + 24;
+    assert_eq!(&avif[mdcv_end..][..cclv.len()], cclv);
+    assert_eq!(&avif[mdcv_end + cclv.len()..][..amve.len()], amve);
+
+    let avif = aviffy.set_width(10).set_height(20).set_bit_depth(10).animation_to_vec(&[AnimationFrame { data: b"frame", duration: 1, is_keyframe: true }], None, 1);
+    assert!(avif.windows(cclv.len()).any(|w| w == cclv));
+    assert!(avif.windows(amve.len()).any(|w| w == amve));
+
+    assert!(Aviffy::new().set_content_color_volume(None, None, None, None).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    assert!(Aviffy::new().set_content_color_volume(None, Some(10), Some(5), None).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    assert!(Aviffy::new().set_ambient_viewing_environment(0, (15635, 16450)).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn gain_map() {
+    let mut gain_map = GainMap::new(b"gainmap".to_vec(), 5, 10, 8, hdr::GainMapMetadata {
+        alternate_hdr_headroom: (13, 10),
+        ..Default::default()
+    });
+    gain_map.set_alternate_content_light_level(1000, 400);
+    let mut aviffy = Aviffy::new();
+    aviffy.set_gain_map(gain_map);
+    let avif = aviffy.to_vec(b"color", Some(b"alpha"), 10, 20, 8);
+
+    // Base image is still the primary item. Strict mode doesn't allow hidden items.
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(b"color", ctx.primary_item_coded_data().unwrap());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(b"color", ctx.primary_item.as_slice());
+
+    let top = test_child_boxes(&avif);
+    assert!(top[0].2.chunks(4).any(|b| b == b"tmap"));
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+
+    let iinf = test_child_boxes(&child("iinf")[6..]);
+    assert!(iinf.iter().any(|b| b.2 == b"\x02\0\0\0\0\x03\0\0tmap\0"));
+    assert!(iinf.iter().any(|b| b.2 == b"\x02\0\0\x01\0\x04\0\0av01\0"));
+    let iref = test_child_boxes(&child("iref")[4..]);
+    assert!(iref.iter().any(|b| b.0 == "dimg" && b.2 == b"\0\x03\0\x02\0\x01\0\x04"));
+    let grpl = test_child_boxes(child("grpl"));
+    assert_eq!(("altr", &b"\0\0\0\0\0\0\0\x05\0\0\0\x02\0\0\0\x03\0\0\0\x01"[..]), (grpl[0].0, grpl[0].2));
+
+    let mut tmap = vec![0, 0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 13, 0, 0, 0, 10];
+    for (n, d) in [(0, 1), (1, 1), (1, 1), (1, 64), (1, 64)] {
+        tmap.extend_from_slice(&[0, 0, 0, n, 0, 0, 0, d]);
+    }
+    assert_eq!(tmap, child("idat"));
+
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert_eq!(b"alphacolorgainmap", mdat);
+
+    let mut invalid = GainMap::new(b"gainmap".to_vec(), 5, 10, 8, hdr::GainMapMetadata {
+        gamma: [(0, 1); 3],
+        ..Default::default()
+    });
+    assert!(Aviffy::new().set_gain_map(invalid.clone()).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    invalid.set_alternate_bit_depth(16);
+    assert!(Aviffy::new().set_gain_map(invalid).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn apple_gain_map() {
+    let avif = Aviffy::new()
+        .set_apple_gain_map(b"gainmap".to_vec(), 5, 10, 8, 3.5)
+        .to_vec(b"color", None, 10, 20, 8);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"color", ctx.primary_item_coded_data().unwrap());
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+    let iinf = test_child_boxes(&child("iinf")[6..]);
+    assert!(iinf.iter().any(|b| b.2 == b"\x02\0\0\0\0\x02\0\0av01\0"));
+    assert!(iinf.iter().any(|b| b.2 == b"\x02\0\0\0\0\x03\0\0mime\0application/rdf+xml\0"));
+    let iref = test_child_boxes(&child("iref")[4..]);
+    assert!(iref.iter().any(|b| b.0 == "auxl" && b.2 == b"\0\x02\0\x01\0\x01"));
+    assert!(iref.iter().any(|b| b.0 == "cdsc" && b.2 == b"\0\x03\0\x01\0\x02"));
+    assert!(avif.windows(APPLE_GAIN_MAP_URN.len()).any(|w| w == APPLE_GAIN_MAP_URN.as_bytes()));
+
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert!(mdat.starts_with(b"colorgainmap<?xpacket"));
+    let xmp = std::str::from_utf8(&mdat[12..]).unwrap();
+    assert!(xmp.contains("<HDRGainMap:HDRGainMapHeadroom>3.5</HDRGainMap:HDRGainMapHeadroom>"));
+
+    assert!(Aviffy::new().set_apple_gain_map(b"gainmap".to_vec(), 0, 10, 8, 3.5).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+
+    // Transformed like the color image
+    let avif = Aviffy::new().set_crop(0, 0, 8, 16).set_mirror(Some(MirrorAxis::Vertical))
+        .set_apple_gain_map(b"gainmap".to_vec(), 10, 20, 8, 3.5).to_vec(b"color", None, 10, 20, 8);
+    let meta = test_child_boxes(&test_child_boxes(&avif)[1].2[4..]);
+    let props = test_item_properties(meta.iter().find(|b| b.0 == "iprp").unwrap().2);
+    assert_eq!(vec!["ispe", "av1C", "auxC", "pixi", "clap", "imir"], props[1].1);
+}
+
+#[test]
+fn depth_map() {
+    let representation = DepthRepresentation {
+        representation_type: DepthRepresentationType::UniformInverseZ,
+        z_near: Some(1.),
+        z_far: Some(2.),
+        d_min: None,
+        d_max: None,
+        disparity_reference_view: 0,
+    };
+    let avif = Aviffy::new()
+        .set_depth_map(b"depth".to_vec(), 10, 20, 8, Some(&representation))
+        .to_vec(b"color", Some(b"alpha"), 10, 20, 8);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"color", ctx.primary_item_coded_data().unwrap());
+    assert_eq!(b"alpha", ctx.alpha_item_coded_data().unwrap());
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+    let iref = test_child_boxes(&child("iref")[4..]);
+    assert!(iref.iter().any(|b| b.0 == "auxl" && b.2 == b"\0\x03\0\x01\0\x01"));
+
+    // Length-prefixed SEI NAL, with an emulation prevention byte in 00 00 01
+    let ipco = test_child_boxes(test_child_boxes(child("iprp"))[0].2);
+    let auxc = ipco.iter().filter(|b| b.0 == "auxC").map(|b| b.2).collect::<Vec<_>>();
+    assert_eq!(2, auxc.len());
+    let mut expected = b"\0\0\0\0urn:mpeg:mpegB:cicp:systems:auxiliary:depth\0\0\0\0\x10".to_vec();
+    expected.extend_from_slice(b"\x4e\x01\xb1\x0a\xc8\xfd\xc0\0\0\x08\x2e\0\0\x03\x01\x80");
+    assert_eq!(expected, auxc[1]);
+
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert_eq!(b"alphacolordepth", mdat);
+
+    // Exactly 80 bits, so no stop bit
+    let aligned = DepthRepresentation {
+        representation_type: DepthRepresentationType::UniformInverseZ,
+        z_near: None,
+        z_far: None,
+        d_min: Some(1.),
+        d_max: Some(2.),
+        disparity_reference_view: 0,
+    };
+    assert_eq!(&b"\0\0\0\x10\x4e\x01\xb1\x0a\x3c\x7e\xe0\0\0\x04\x17\0\0\x03\0\x80"[..], aligned.aux_subtype());
+
+    let avif = Aviffy::new().set_depth_map(b"depth".to_vec(), 5, 5, 8, None).to_vec(b"color", None, 10, 20, 8);
+    assert!(avif.windows(DEPTH_URN.len() + 1).any(|w| w == b"urn:mpeg:mpegB:cicp:systems:auxiliary:depth\0"));
+
+    // Transformed like the color image, with the crop scaled to the depth map's size
+    let avif = Aviffy::new().set_crop(2, 4, 6, 12).set_rotation(Rotation::D90)
+        .set_depth_map(b"depth".to_vec(), 5, 10, 8, None).to_vec(b"color", None, 10, 20, 8);
+    let meta = test_child_boxes(&test_child_boxes(&avif)[1].2[4..]);
+    let props = test_item_properties(meta.iter().find(|b| b.0 == "iprp").unwrap().2);
+    assert_eq!((2, vec!["ispe", "av1C", "auxC", "pixi", "clap", "irot"]), props[1]);
+    assert!(avif.windows(40).any(|w| w == b"\0\0\0\x28clap\0\0\0\x03\0\0\0\x01\0\0\0\x06\0\0\0\x01\0\0\0\0\0\0\0\x01\0\0\0\0\0\0\0\x01"));
+    assert!(Aviffy::new().set_crop(1, 4, 6, 12).set_depth_map(b"depth".to_vec(), 5, 10, 8, None).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn thumbnails() {
+    let avif = Aviffy::new()
+        .set_rotation(Rotation::D90)
+        .set_crop(0, 0, 9, 19)
+        .add_thumbnail(b"thumb1".to_vec(), None, 4, 8, 8)
+        .add_thumbnail(b"thumb2".to_vec(), Some(b"talpha2".to_vec()), 2, 4, 10)
+        .to_vec(b"color", Some(b"alpha"), 10, 20, 8);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(b"color", ctx.primary_item.as_slice());
+    assert_eq!(b"alpha", ctx.alpha_item.as_deref().unwrap());
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+    let iref = test_child_boxes(&child("iref")[4..]);
+    let refs = iref.iter().map(|b| (b.0, b.2)).collect::<Vec<_>>();
+    assert!(refs.contains(&("thmb", b"\0\x03\0\x01\0\x01")));
+    assert!(refs.contains(&("thmb", b"\0\x04\0\x01\0\x01")));
+    assert!(refs.contains(&("auxl", b"\0\x05\0\x01\0\x04")));
+
+    // Each thumbnail has its own size, and is rotated but not cropped
+    let iprp = test_child_boxes(child("iprp"));
+    let ipco = test_child_boxes(iprp[0].2);
+    let prop_name = |i: u8| ipco[usize::from(i & 0x7F) - 1].0;
+    let ipma = iprp[1].2;
+    let mut pos = 8;
+    let mut props = Vec::new();
+    while pos < ipma.len() {
+        let (id, count) = (ipma[pos + 1], usize::from(ipma[pos + 2]));
+        props.push((id, ipma[pos + 3..][..count].iter().map(|&p| prop_name(p)).collect::<Vec<_>>()));
+        pos += 3 + count;
+    }
+    assert!(props.contains(&(1, vec!["ispe", "av1C", "pixi", "clap", "irot"])));
+    assert!(props.contains(&(3, vec!["ispe", "av1C", "pixi", "irot"])));
+    assert!(props.contains(&(5, vec!["ispe", "av1C", "auxC", "pixi", "irot"])));
+
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert_eq!(b"alphacolorthumb1talpha2thumb2", mdat);
+
+    let avif = Aviffy::new().add_thumbnail(b"thumb".to_vec(), Some(b"talpha".to_vec()), 4, 8, 8).to_vec(b"color", Some(b"alpha"), 10, 20, 8);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"color", ctx.primary_item_coded_data().unwrap());
+    assert_eq!(b"alpha", ctx.alpha_item_coded_data().unwrap());
+
+    assert!(Aviffy::new().add_thumbnail(b"thumb".to_vec(), None, 4, 0, 8).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn alternatives() {
+    use constants::{ColorPrimaries as P, MatrixCoefficients as M, TransferCharacteristics as T};
+
+    let mut hdr = Alternative::new(b"hdr".to_vec(), 10);
+    hdr.set_color(P::Bt2020, T::Smpte2084, M::Bt2020Ncl)
+        .set_alpha(b"hdralpha".to_vec())
+        .set_mastering_display_metadata(&hdr::MasteringDisplay::bt2020_1000_nits());
+    let avif = Aviffy::new()
+        .add_alternative(hdr)
+        .add_alternative(Alternative::new(b"10bit".to_vec(), 10))
+        .to_vec(b"sdr", Some(b"alpha"), 10, 20, 8);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"sdr", ctx.primary_item_coded_data().unwrap());
+    assert_eq!(b"alpha", ctx.alpha_item_coded_data().unwrap());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(b"sdr", ctx.primary_item.as_slice());
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+    let iref = test_child_boxes(&child("iref")[4..]);
+    assert!(iref.iter().any(|b| b.0 == "auxl" && b.2 == b"\0\x04\0\x01\0\x03"));
+
+    // Group ID after all item IDs, then the preferred alternatives, and the primary item last
+    let grpl = test_child_boxes(child("grpl"));
+    assert_eq!(("altr", &b"\0\0\0\0\0\0\0\x06\0\0\0\x03\0\0\0\x03\0\0\0\x05\0\0\0\x01"[..]), (grpl[0].0, grpl[0].2));
+
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert_eq!(b"alphasdrhdralphahdr10bit", mdat);
+
+    let iprp = test_child_boxes(child("iprp"));
+    assert_eq!([1, 2, 3, 4, 5], test_ipma_item_ids(iprp[1].2).as_slice());
+
+    // With grids, the alpha grid and alternatives are added after the color tiles, but ipma is ordered by item ID
+    let mut alt = Alternative::new(b"alt".to_vec(), 10);
+    alt.set_alpha(b"altalpha".to_vec());
+    let layout = GridLayout { rows: 1, columns: 2, tile_width: 64, tile_height: 64 };
+    let avif = Aviffy::new().set_width(100).set_height(64).set_bit_depth(8).add_alternative(alt)
+        .grid_to_vec(&layout, &[b"color0", b"color1"], Some(&[b"alpha0", b"alpha1"]));
+    let meta = test_child_boxes(&test_child_boxes(&avif)[1].2[4..]);
+    let iprp = test_child_boxes(meta.iter().find(|b| b.0 == "iprp").unwrap().2);
+    assert_eq!([1, 2, 4, 5, 6, 7, 8, 9], test_ipma_item_ids(iprp[1].2).as_slice());
+
+    assert!(Aviffy::new().add_alternative(Alternative::new(b"16bit".to_vec(), 16)).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    let avif = Aviffy::new().to_vec(b"color", None, 10, 20, 8);
+    assert!(!avif.windows(4).any(|w| w == b"grpl"));
+}
+
+#[test]
+fn progressive_layers() {
+    // sequence header, then a frame in each of two spatial layers
+    let mut av1 = vec![0x0A, 3, 0, 0, 0];
+    av1.extend_from_slice(&[0x36, 0, 4, 1, 2, 3, 4]);
+    av1.extend_from_slice(&[0x36, 0x08, 130, 1]);
+    av1.extend_from_slice(&[0; 130]);
+    let sizes = av1_layer_sizes(&av1).unwrap();
+    assert_eq!([12, 134], sizes.as_slice());
+    assert_eq!(vec![5], av1_layer_sizes(&av1[..5]).unwrap());
+    // temporal delimiters start layers too
+    assert_eq!(vec![5, 2, 3], av1_layer_sizes(&[0x0A, 3, 0, 0, 0, 0x12, 0, 0x12, 1, 0]).unwrap());
+    assert!(av1_layer_sizes(&[0x0A, 30, 0]).is_err());
+
+    let avif = Aviffy::new().set_progressive_layers(&sizes).to_vec(&av1, None, 10, 20, 8);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(av1, ctx.primary_item.as_slice());
+
+    let top = test_child_boxes(&avif);
+    assert!(top[0].2.chunks(4).any(|b| b == b"mif2"));
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let iprp = test_child_boxes(meta.iter().find(|b| b.0 == "iprp").unwrap().2);
+    let ipco = test_child_boxes(iprp[0].2);
+    let a1lx = ipco.iter().position(|b| b.0 == "a1lx").unwrap();
+    assert_eq!(b"\0\0\x0c\0\0\0\0", ipco[a1lx].2);
+    let lsel = ipco.iter().position(|b| b.0 == "lsel").unwrap();
+    assert_eq!(b"\xff\xff", ipco[lsel].2);
+    // lsel is essential, a1lx isn't
+    assert!(iprp[1].2.ends_with(&[a1lx as u8 + 1, (lsel as u8 + 1) | 0x80]));
+
+    assert!(Aviffy::new().set_progressive_layers(&[12, 100]).write(&mut vec![], &av1, None, 10, 20, 8).is_err());
+    assert!(Aviffy::new().set_progressive_layers(&[146]).write(&mut vec![], &av1, None, 10, 20, 8).is_err());
+    let avif = Aviffy::new().set_progressive_layers(&sizes).set_progressive_layers(&[]).to_vec(&av1, None, 10, 20, 8);
+    assert!(!avif.windows(4).any(|w| w == b"a1lx" || w == b"mif2"));
+}
+
+#[test]
+fn operating_point() {
+    // Sequence header with 3 operating points: profile 0, no timing info, operating_points_cnt_minus_1 = 2, then anything
+    let av1 = [0x0A, 2, 0b0000_0000, 0b0010_0000, 0x32, 1, 0];
+    let avif = Aviffy::new().set_operating_point(Some(2)).to_vec(&av1, None, 10, 20, 8);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(av1, ctx.primary_item.as_slice());
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let iprp = test_child_boxes(meta.iter().find(|b| b.0 == "iprp").unwrap().2);
+    let ipco = test_child_boxes(iprp[0].2);
+    let a1op = ipco.iter().position(|b| b.0 == "a1op").unwrap();
+    assert_eq!(b"\x02", ipco[a1op].2);
+    assert!(iprp[1].2.ends_with(&[(a1op as u8 + 1) | 0x80]));
+
+    assert!(Aviffy::new().set_operating_point(Some(3)).write(&mut vec![], &av1, None, 10, 20, 8).is_err());
+    assert!(Aviffy::new().set_operating_point(Some(0)).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+    // reduced still picture header has one operating point
+    assert!(Aviffy::new().set_operating_point(Some(0)).write(&mut vec![], &[0x0A, 1, 0b0000_1000], None, 10, 20, 8).is_ok());
+    assert!(Aviffy::new().set_operating_point(Some(1)).write(&mut vec![], &[0x0A, 1, 0b0000_1000], None, 10, 20, 8).is_err());
+    // timing and decoder model info are skipped
+    let mut bits = String::from("000" /* profile */);
+    bits += "0" /* still */;
+    bits += "0" /* reduced */;
+    bits += "1" /* timing_info_present */;
+    bits += &"0".repeat(64);
+    bits += "1" /* equal_picture_interval */;
+    bits += "00100" /* uvlc 3 */;
+    bits += "1" /* decoder_model_info_present */;
+    bits += &"0".repeat(47);
+    bits += "0" /* initial_display_delay_present */;
+    bits += "00001" /* 2 operating points */;
+    while bits.len() % 8 != 0 {
+        bits += "0";
+    }
+    let payload = bits.as_bytes().chunks(8).map(|b| u8::from_str_radix(std::str::from_utf8(b).unwrap(), 2).unwrap()).collect::<Vec<_>>();
+    let mut av1 = vec![0x0A, payload.len() as u8];
+    av1.extend_from_slice(&payload);
+    assert!(Aviffy::new().set_operating_point(Some(1)).write(&mut vec![], &av1, None, 10, 20, 8).is_ok());
+    assert!(Aviffy::new().set_operating_point(Some(2)).write(&mut vec![], &av1, None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn xmp_item() {
+    let xmp = br#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?><x:xmpmeta xmlns:x="adobe:ns:meta/"/><?xpacket end="w"?>"#;
+    let avif = Aviffy::new().set_exif(test_tiff_exif()).set_xmp(xmp.to_vec()).to_vec(b"color", Some(b"alpha"), 10, 20, 8);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(b"color", ctx.primary_item_coded_data().unwrap());
+
+    let top = test_child_boxes(&avif);
+    let meta = test_child_boxes(&top[1].2[4..]);
+    let child = |name: &str| meta.iter().find(|b| b.0 == name).unwrap().2;
+    let iinf = test_child_boxes(&child("iinf")[6..]);
+    assert!(iinf.iter().any(|b| b.2 == b"\x02\0\0\0\0\x04\0\0mime\0application/rdf+xml\0"));
+    let iref = test_child_boxes(&child("iref")[4..]);
+    assert!(iref.iter().any(|b| b.0 == "cdsc" && b.2 == b"\0\x04\0\x01\0\x01"));
+
+    // stored after the image
+    let mdat = top.iter().find(|b| b.0 == "mdat").unwrap().2;
+    assert!(mdat.ends_with(&[&b"alphacolor"[..], xmp].concat()));
+}
+
+#[test]
+fn icc_profile_with_nclx() {
+    let mut icc = vec![0; 132];
+    icc[36..40].copy_from_slice(b"acsp");
+    icc[128..132].copy_from_slice(b"test");
+    let avif = Aviffy::new()
+        .set_matrix_coefficients(constants::MatrixCoefficients::Bt709)
+        .set_icc_profile(icc.clone())
+        .to_vec(b"color", None, 10, 20, 8);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Strict).unwrap();
+    assert_eq!(icc.as_slice(), ctx.icc_colour_information().unwrap().unwrap());
+    assert!(!ctx.nclx_colour_information_ptr().unwrap().unwrap().is_null());
+
+    let avif = Aviffy::new().set_restricted_icc_profile(icc.clone()).to_vec(b"color", None, 10, 20, 8);
+    assert!(avif.windows(8).any(|w| w == b"colrrICC"));
+    assert!(!avif.windows(8).any(|w| w == b"colrnclx"));
+
+    assert!(Aviffy::new().set_icc_profile(vec![1, 2, 3]).write(&mut vec![], b"color", None, 10, 20, 8).is_err());
+}
+
+#[test]
+fn cicp_from_icc() {
+    use constants::*;
+
+    let xyz = |v: [f64; 3]| [&b"XYZ \0\0\0\0"[..], &v.map(|v| ((v * 65536.).round() as i32).to_be_bytes()).concat()].concat();
+    let srgb_trc = [&b"para\0\0\0\0\0\x03\0\0"[..], &[2.4, 1. / 1.055, 0.055 / 1.055, 1. / 12.92, 0.04045].map(|v: f64| ((v * 65536.).round() as i32).to_be_bytes()).concat()].concat();
+    let gamma22_trc = b"curv\0\0\0\0\0\0\0\x01\x02\x33".to_vec();
+    let d50 = xyz([0.9642, 1., 0.8249]);
+    // from the ICC's sRGB v4 profile
+    let srgb = [xyz([0.436_065, 0.222_488, 0.013_916]), xyz([0.385_147, 0.716_873, 0.097_076]), xyz([0.143_066, 0.060_608, 0.714_096])];
+    // from Apple's Display P3 profile
+    let p3 = [xyz([0.515_121, 0.241_196, -0.001_053]), xyz([0.291_977, 0.692_245, 0.041_885]), xyz([0.157_104, 0.066_574, 0.784_073])];
+
+    let profile = |colorants: &[Vec<u8>; 3], trc: &[u8]| test_icc_profile(&[
+        (*b"wtpt", &d50), (*b"rXYZ", &colorants[0]), (*b"gXYZ", &colorants[1]), (*b"bXYZ", &colorants[2]),
+        (*b"rTRC", trc), (*b"gTRC", trc), (*b"bTRC", trc),
+    ]);
+    assert_eq!(Some((ColorPrimaries::Bt709, TransferCharacteristics::Srgb, MatrixCoefficients::Unspecified, None)), cicp_from_icc_profile(&profile(&srgb, &srgb_trc)));
+    assert_eq!(Some((ColorPrimaries::DisplayP3, TransferCharacteristics::Srgb, MatrixCoefficients::Unspecified, None)), cicp_from_icc_profile(&profile(&p3, &srgb_trc)));
+    assert_eq!(Some((ColorPrimaries::Bt709, TransferCharacteristics::Linear, MatrixCoefficients::Unspecified, None)), cicp_from_icc_profile(&profile(&srgb, b"curv\0\0\0\0\0\0\0\0")));
+    assert_eq!(None, cicp_from_icc_profile(&profile(&srgb, &gamma22_trc)));
+    assert_eq!(None, cicp_from_icc_profile(&profile(&[srgb[0].clone(), p3[1].clone(), srgb[2].clone()], &srgb_trc)));
+
+    let bt2100_pq = test_icc_profile(&[(*b"cicp", b"cicp\0\0\0\0\x09\x10\0\x01")]);
+    assert_eq!(Some((ColorPrimaries::Bt2020, TransferCharacteristics::Smpte2084, MatrixCoefficients::Rgb, Some(true))), cicp_from_icc_profile(&bt2100_pq));
+    let bt2100_pq_ycbcr = test_icc_profile(&[(*b"cicp", b"cicp\0\0\0\0\x09\x10\x09\0")]);
+    assert_eq!(Some((ColorPrimaries::Bt2020, TransferCharacteristics::Smpte2084, MatrixCoefficients::Bt2020Ncl, Some(false))), cicp_from_icc_profile(&bt2100_pq_ycbcr));
+    assert_eq!(None, cicp_from_icc_profile(b"not a profile"));
+
+    let pq_trc = [&b"curv\0\0\0\0\0\0\x04\0"[..], &(0..1024).flat_map(|i| {
+        const M1: f64 = 2610. / 16384.;
+        const M2: f64 = 2523. / 32.;
+        let p = (f64::from(i) / 1023.).powf(1. / M2);
+        let linear = ((p - 0.8359375).max(0.) / (18.8515625 - 18.6875 * p)).powf(1. / M1);
+        ((linear * 65535.).round() as u16).to_be_bytes()
+    }).collect::<Vec<_>>()].concat();
+    assert_eq!(Some((ColorPrimaries::Bt709, TransferCharacteristics::Smpte2084, MatrixCoefficients::Unspecified, None)), cicp_from_icc_profile(&profile(&srgb, &pq_trc)));
+    let hlg_trc = [&b"curv\0\0\0\0\0\0\x04\0"[..], &(0..1024).flat_map(|i| {
+        let v = f64::from(i) / 1023.;
+        let linear = if v <= 0.5 { v * v / 3. } else { (((v - 0.55991073) / 0.17883277).exp() + 0.28466892) / 12. };
+        ((linear.min(1.) * 65535.).round() as u16).to_be_bytes()
+    }).collect::<Vec<_>>()].concat();
+    assert_eq!(Some((ColorPrimaries::DisplayP3, TransferCharacteristics::Hlg, MatrixCoefficients::Unspecified, None)), cicp_from_icc_profile(&profile(&p3, &hlg_trc)));
+
+    let mut aviffy = Aviffy::new();
+    let avif = aviffy.set_icc_profile_or_cicp(profile(&p3, &srgb_trc)).to_vec(b"color", None, 10, 20, 8);
+    assert!(avif.windows(15).any(|w| w == b"colrnclx\0\x0c\0\x0d\0\x06\x80"));
+    assert!(!avif.windows(8).any(|w| w == b"colrprof"));
+    let avif = aviffy.set_icc_profile_or_cicp(profile(&srgb, &gamma22_trc)).to_vec(b"color", None, 10, 20, 8);
+    assert!(avif.windows(8).any(|w| w == b"colrprof"));
+}
+
+#[test]
+fn code_point_conversions() {
+    use constants::*;
+
+    for code in 0..300 {
+        if let Ok(cp) = ColorPrimaries::try_from(code) {
+            assert_eq!(code, u16::from(cp));
+            assert_eq!(cp == ColorPrimaries::Unspecified, cp.primaries().is_none());
+        }
+        if let Ok(tc) = TransferCharacteristics::try_from(code) {
+            assert_eq!(code, u16::from(tc));
+        }
+        if let Ok(mc) = MatrixCoefficients::try_from(code) {
+            assert_eq!(code, u16::from(mc));
+        }
+    }
+    assert_eq!(Ok(ColorPrimaries::Ebu3213), ColorPrimaries::try_from(22));
+    assert_eq!(Err(UnknownCodePoint(3)), ColorPrimaries::try_from(3));
+    assert_eq!(Ok(TransferCharacteristics::Hlg), TransferCharacteristics::try_from(18));
+    assert_eq!(Err(UnknownCodePoint(19)), TransferCharacteristics::try_from(19));
+    assert_eq!(Ok(MatrixCoefficients::Ictcp), MatrixCoefficients::try_from(14));
+    assert_eq!(Err(UnknownCodePoint(3)), MatrixCoefficients::try_from(3));
+
+    let (primaries, white_point) = ColorPrimaries::Bt2020.mastering_display_primaries().unwrap();
+    assert_eq!([(8500, 39850), (6550, 2300), (35400, 14600)], primaries);
+    assert_eq!((15635, 16450), white_point);
+}
+
+#[test]
+fn mastering_display_presets() {
+    use hdr::MasteringDisplay;
+
+    let avif = Aviffy::new()
+        .set_mastering_display_metadata(&MasteringDisplay::p3_d65_1000_nits())
+        .to_vec(b"color", None, 10, 20, 10);
+    let mdcv = avif_parse::read_avif(&mut avif.as_slice()).unwrap().mastering_display.unwrap();
+    assert_eq!([(13250, 34500), (7500, 3000), (34000, 16000)], mdcv.primaries);
+    assert_eq!((15635, 16450), mdcv.white_point);
+    assert_eq!(10_000_000, mdcv.max_luminance);
+    assert_eq!(1, mdcv.min_luminance);
+
+    let custom = MasteringDisplay::new((0.708, 0.292), (0.170, 0.797), (0.131, 0.046), (0.3127, 0.3290), 4000., 0.005).unwrap();
+    assert_eq!(MasteringDisplay::bt2020_4000_nits(), custom);
+    assert_eq!(50, custom.mdcv().min_luminance);
+    assert_eq!(MasteringDisplay::bt2020_1000_nits().mdcv().primaries, [(8500, 39850), (6550, 2300), (35400, 14600)]);
+
+    assert!(MasteringDisplay::new((0.7, 0.3), (0.2, 0.8), (0.1, 0.05), (0.3, 0.3), 100., 100.).is_err());
+    assert!(MasteringDisplay::new((0.7, 0.3), (0.2, 0.8), (0.1, 0.05), (0.3, 0.3), 20000., 0.).is_err());
+    assert!(MasteringDisplay::new((1.7, 0.3), (0.2, 0.8), (0.1, 0.05), (0.3, 0.3), 1000., 0.).is_err());
+    assert!(MasteringDisplay::new((0.7, 0.3), (0.2, 0.8), (0.1, 0.05), (0.3, f64::NAN), 1000., 0.).is_err());
+    assert!(MasteringDisplay::from_color_primaries(constants::ColorPrimaries::Unspecified, 1000., 0.).is_err());
+}
+
+#[test]
+fn content_light_level_analysis() {
+    use constants::{ColorPrimaries as P, TransferCharacteristics as T};
+    use hdr::ContentLightLevel;
+
+    // PQ 0.508 ≈ 100 cd/m², 0.752 ≈ 1000 cd/m²
+    let mut pq = ContentLightLevel::new();
+    pq.add_frame(&[0.508, 0.], &[0., 0.], &[0., 0.752], T::Smpte2084, P::Bt2020).unwrap();
+    pq.add_frame(&[0.508, 0.508], &[0.508, 0.508], &[0.508, 0.508], T::Smpte2084, P::Bt2020).unwrap();
+    assert!((995..=1005).contains(&pq.max_content_light_level()), "{}", pq.max_content_light_level());
+    assert!((548..=552).contains(&pq.max_pic_average_light_level()), "{}", pq.max_pic_average_light_level());
+    assert_eq!(10000, ContentLightLevel::new().add_frame(&[1.], &[0.], &[0.], T::Smpte2084, P::Unspecified).unwrap().max_content_light_level());
+
+    let mut linear = ContentLightLevel::new();
+    linear.set_peak_luminance(400.).add_frame(&[0.5, 1.], &[0.25, 0.], &[0., 0.], T::Linear, P::Bt709).unwrap();
+    assert_eq!((400, 300), (linear.max_content_light_level(), linear.max_pic_average_light_level()));
+
+    // HLG white at signal 1.0 reaches the nominal peak
+    let mut hlg = ContentLightLevel::new();
+    hlg.add_frame(&[1.], &[1.], &[1.], T::Hlg, P::Bt2020).unwrap();
+    assert_eq!(1000, hlg.max_content_light_level());
+    assert!(ContentLightLevel::new().add_frame(&[1.], &[1.], &[1.], T::Hlg, P::Unspecified).is_err());
+    assert!(ContentLightLevel::new().add_frame(&[1.], &[1.], &[1.], T::Srgb, P::Bt709).is_err());
+    assert!(ContentLightLevel::new().add_frame(&[1.], &[1.], &[], T::Linear, P::Bt709).is_err());
+
+    let avif = Aviffy::new()
+        .set_content_light_level_metadata(&linear)
+        .to_vec(b"color", None, 10, 20, 10);
+    let clli = avif_parse::read_avif(&mut avif.as_slice()).unwrap().content_light_level.unwrap();
+    assert_eq!((400, 300), (clli.max_content_light_level, clli.max_pic_average_light_level));
+}
+
+/// (type, offset of the box, box payload)
+#[cfg(test)]
+fn test_child_boxes(mut data: &[u8]) -> Vec<(&str, usize, &[u8])> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        boxes.push((std::str::from_utf8(&data[4..8]).unwrap(), offset, &data[8..size]));
+        data = &data[size..];
+        offset += size;
+    }
+    boxes
+}
+
+/// Item IDs of `ipma` entries (version 0 with 7-bit property indices), in order
+#[cfg(test)]
+fn test_ipma_item_ids(mut ipma: &[u8]) -> Vec<u16> {
+    let mut ids = Vec::new();
+    ipma = &ipma[8..];
+    while !ipma.is_empty() {
+        ids.push(u16::from_be_bytes([ipma[0], ipma[1]]));
+        ipma = &ipma[3 + usize::from(ipma[2])..];
+    }
+    ids
+}
+
+/// Property types of every `ipma` entry, by item ID, from the `iprp` box's payload
+#[cfg(test)]
+fn test_item_properties(iprp: &[u8]) -> Vec<(u16, Vec<&str>)> {
+    let iprp = test_child_boxes(iprp);
+    let ipco = test_child_boxes(iprp[0].2);
+    let ids = test_ipma_item_ids(iprp[1].2);
+    let mut ipma = &iprp[1].2[8..];
+    ids.into_iter().map(|id| {
+        let count = usize::from(ipma[2]);
+        let props = ipma[3..3 + count].iter().map(|&p| ipco[usize::from(p & 0x7F) - 1].0).collect();
+        ipma = &ipma[3 + count..];
+        (id, props)
+    }).collect()
+}
+
+/// Minimal RGB display profile with the given tags
+#[cfg(test)]
+fn test_icc_profile(tags: &[([u8; 4], &[u8])]) -> Vec<u8> {
+    let mut icc = vec![0; 128];
+    icc[12..16].copy_from_slice(b"mntr");
+    icc[16..20].copy_from_slice(b"RGB ");
+    icc[20..24].copy_from_slice(b"XYZ ");
+    icc[36..40].copy_from_slice(b"acsp");
+    icc.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+    let mut offset = icc.len() + tags.len() * 12;
+    for (signature, data) in tags {
+        icc.extend_from_slice(signature);
+        icc.extend_from_slice(&(offset as u32).to_be_bytes());
+        icc.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        offset += data.len();
+    }
+    for (_, data) in tags {
+        icc.extend_from_slice(data);
+    }
+    let len = icc.len() as u32;
+    icc[..4].copy_from_slice(&len.to_be_bytes());
+    icc
+}
+
 #[cfg(test)]
 fn test_heif_exif(tiff_exif: &[u8]) -> Vec<u8> {
     let mut heif_exif = 0_u32.to_be_bytes().to_vec();
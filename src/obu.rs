@@ -0,0 +1,146 @@
+//! Just enough of AV1 OBU (Open Bitstream Unit) parsing to find layers and operating points in the coded data
+
+use std::io;
+
+pub const OBU_SEQUENCE_HEADER: u8 = 1;
+pub const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Obu<'data> {
+    pub obu_type: u8,
+    /// From the extension header, if present
+    pub spatial_id: Option<u8>,
+    /// Position of the OBU (including its header) in the data
+    pub offset: usize,
+    pub payload: &'data [u8],
+}
+
+/// Iterates OBUs in the low-overhead bitstream format. Only the last OBU may lack the size field.
+pub fn obus(mut data: &[u8]) -> impl Iterator<Item = io::Result<Obu<'_>>> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        let res = parse_obu(data, offset);
+        match res {
+            Ok((obu, len)) => {
+                data = &data[len..];
+                offset += len;
+                Some(Ok(obu))
+            },
+            Err(e) => {
+                data = &[];
+                Some(Err(e))
+            },
+        }
+    })
+}
+
+fn parse_obu(data: &[u8], offset: usize) -> io::Result<(Obu<'_>, usize)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid AV1 OBU");
+    let header = *data.first().ok_or_else(invalid)?;
+    if header & 0x80 != 0 {
+        return Err(invalid());
+    }
+    let obu_type = (header >> 3) & 0x0F;
+    let has_extension = header & 0x04 != 0;
+    let has_size = header & 0x02 != 0;
+    let mut pos = 1;
+    let spatial_id = if has_extension {
+        let extension = *data.get(pos).ok_or_else(invalid)?;
+        pos += 1;
+        Some((extension >> 3) & 0x03)
+    } else {
+        None
+    };
+    let size = if has_size {
+        let (size, len) = leb128(&data[pos..]).ok_or_else(invalid)?;
+        pos += len;
+        usize::try_from(size).map_err(|_| invalid())?
+    } else {
+        data.len() - pos
+    };
+    let payload = data.get(pos..pos.checked_add(size).ok_or_else(invalid)?).ok_or_else(invalid)?;
+    Ok((Obu { obu_type, spatial_id, offset, payload }, pos + size))
+}
+
+/// Number of operating points declared in the first sequence header, or `None` if there's no sequence header
+pub fn operating_points_count(av1_data: &[u8]) -> io::Result<Option<u8>> {
+    for obu in obus(av1_data) {
+        let obu = obu?;
+        if obu.obu_type == OBU_SEQUENCE_HEADER {
+            return parse_operating_points_count(obu.payload)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid AV1 sequence header"))
+                .map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the sequence header up to `operating_points_cnt_minus_1`
+fn parse_operating_points_count(sequence_header: &[u8]) -> Option<u8> {
+    let mut bits = BitReader { data: sequence_header, pos: 0 };
+    let _seq_profile = bits.read(3)?;
+    let _still_picture = bits.read(1)?;
+    let reduced_still_picture_header = bits.read(1)? != 0;
+    if reduced_still_picture_header {
+        return Some(1);
+    }
+    let timing_info_present = bits.read(1)? != 0;
+    if timing_info_present {
+        let _num_units_in_display_tick = bits.read(32)?;
+        let _time_scale = bits.read(32)?;
+        let equal_picture_interval = bits.read(1)? != 0;
+        if equal_picture_interval {
+            bits.read_uvlc()?;
+        }
+        let decoder_model_info_present = bits.read(1)? != 0;
+        if decoder_model_info_present {
+            // buffer_delay_length_minus_1, num_units_in_decoding_tick, buffer_removal_time_length_minus_1, frame_presentation_time_length_minus_1
+            bits.read(5 + 32 + 5 + 5)?;
+        }
+    }
+    let _initial_display_delay_present = bits.read(1)?;
+    Some(bits.read(5)? as u8 + 1)
+}
+
+struct BitReader<'data> {
+    data: &'data [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    /// MSB-first, up to 64 bits
+    fn read(&mut self, bits: usize) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            value = (value << 1) | u64::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn read_uvlc(&mut self) -> Option<u64> {
+        let mut leading_zeros = 0;
+        while self.read(1)? == 0 {
+            leading_zeros += 1;
+        }
+        if leading_zeros >= 32 {
+            return Some(u64::from(u32::MAX));
+        }
+        Some(self.read(leading_zeros)? + (1 << leading_zeros) - 1)
+    }
+}
+
+fn leb128(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, &byte) in data.iter().take(8).enumerate() {
+        value |= u64::from(byte & 0x7F) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
@@ -0,0 +1,912 @@
+//! Boxes for AVIF image sequences (animations). These live in `moov`, the video side of ISO-BMFF,
+//! which is completely separate from the `meta` box used for still images.
+
+use crate::boxes::{AmveBox, Av1CBox, CclvBox, ClliBox, ColrBox, ColrIccBox, FourCC, FtypBox, HdlrBox, IlocBox, MdcvBox, MetaBox, MpegBox, BASIC_BOX_SIZE, FULL_BOX_SIZE};
+use crate::writer::{Writer, WriterBackend, IO};
+use crate::AnimationFrame;
+use arrayvec::ArrayVec;
+use std::io;
+use std::io::Write;
+use std::num::NonZeroU32;
+
+/// 16.16 fixed-point 1.0, and 2.30 fixed-point 1.0 at the end
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+#[derive(Debug, Clone)]
+pub struct AvisFile<'data> {
+    pub ftyp: FtypBox,
+    /// Optional still image (poster) for decoders that don't support animation
+    pub meta: Option<MetaBox<'data>>,
+    pub moov: MoovBox,
+    pub mdat: SequenceMdatBox<'data>,
+}
+
+impl AvisFile<'_> {
+    /// Where the first item or sample starts inside the `mdat` box, for `iloc`'s and `stco`'s offsets
+    fn mdat_payload_start_offset(&self) -> usize {
+        self.ftyp.len() + self.meta.as_ref().map_or(0, |m| m.len()) + self.moov.len()
+            + BASIC_BOX_SIZE // mdat head
+    }
+
+    /// Same problem as `iloc`: `moov` has to know where samples are in `mdat` that comes after it.
+    /// The number of offsets is known up front, so this doesn't change the size of `moov`.
+    fn fix_chunk_offsets(&mut self) -> io::Result<()> {
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "animation too large");
+
+        let start_offset = self.mdat_payload_start_offset();
+        let mut next_start = start_offset;
+        if let Some(meta) = &mut self.meta {
+            meta.iloc.absolute_offset_start = NonZeroU32::new(u32::try_from(start_offset).map_err(|_| too_large())?);
+            next_start += meta.iloc.items.iter().flat_map(|i| &i.extents).map(|ex| ex.data.len()).sum::<usize>();
+        }
+
+        for t in &mut self.moov.tracks {
+            t.mdia.minf.stbl.stco.offsets.clear();
+        }
+        for chunk in &self.mdat.chunks {
+            let offset = if chunk.in_poster {
+                self.meta.as_ref().and_then(|meta| iloc_offset_of(&meta.iloc, chunk.data))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "poster frame not found"))?
+            } else {
+                let offset = next_start;
+                next_start += chunk.data.len();
+                offset
+            };
+            let offset = u32::try_from(offset).map_err(|_| too_large())?;
+            self.moov.tracks[chunk.track].mdia.minf.stbl.stco.offsets.push(offset);
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.fix_chunk_offsets()?;
+
+        out.try_reserve_exact(self.mdat_payload_start_offset())?;
+        let mut w = Writer::new(out);
+        self.ftyp.write(&mut w).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        if let Some(meta) = &self.meta {
+            meta.write(&mut w).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        }
+        self.moov.write(&mut w).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        Ok(())
+    }
+
+    pub fn file_size(&self) -> usize {
+        let iloc = self.meta.as_ref().map(|m| &m.iloc);
+        self.ftyp.len() + self.meta.as_ref().map_or(0, |m| m.len()) + self.moov.len() + self.mdat.len(iloc)
+    }
+
+    pub fn write_to_vec(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let expected_file_size = self.file_size();
+        out.try_reserve_exact(expected_file_size)?;
+        let initial = out.len();
+        self.write_header(out)?;
+
+        let _ = self.mdat.write(&mut Writer::new(out), self.meta.as_ref().map(|m| &m.iloc));
+        let written = out.len() - initial;
+        debug_assert_eq!(expected_file_size, written);
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&mut self, mut out: W) -> io::Result<()> {
+        let mut tmp = Vec::new();
+
+        self.write_header(&mut tmp)?;
+        out.write_all(&tmp)?;
+        drop(tmp);
+
+        self.mdat.write(&mut Writer::new(&mut IO(out)), self.meta.as_ref().map(|m| &m.iloc))
+    }
+}
+
+/// When the poster is the first frame, the sample has the same data as the item,
+/// and it's stored only once.
+fn iloc_offset_of(iloc: &IlocBox<'_>, data: &[u8]) -> Option<usize> {
+    let mut offset = iloc.absolute_offset_start?.get() as usize;
+    for ex in iloc.items.iter().flat_map(|i| &i.extents) {
+        if ex.data == data {
+            return Some(offset);
+        }
+        offset += ex.data.len();
+    }
+    None
+}
+
+/// Movie box
+#[derive(Debug, Clone)]
+pub struct MoovBox {
+    pub mvhd: MvhdBox,
+    pub tracks: ArrayVec<TrakBox, 2>,
+    /// Only in fragmented files
+    pub mvex: Option<MvexBox>,
+}
+
+impl MpegBox for MoovBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + self.mvhd.len()
+            + self.tracks.iter().map(|t| t.len()).sum::<usize>()
+            + self.mvex.as_ref().map_or(0, |b| b.len())
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"moov")?;
+        self.mvhd.write(&mut b)?;
+        for t in &self.tracks {
+            t.write(&mut b)?;
+        }
+        if let Some(mvex) = &self.mvex {
+            mvex.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Movie Header box
+#[derive(Debug, Copy, Clone)]
+pub struct MvhdBox {
+    pub timescale: u32,
+    /// Duration of the longest track. `u32::MAX` means indefinite.
+    pub duration: u32,
+    pub next_track_id: u32,
+}
+
+impl MpegBox for MvhdBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE
+        + 4 + 4 // creation/modification time
+        + 4 // timescale
+        + 4 // duration
+        + 4 + 2 // rate, volume
+        + 2 + 4 * 2 // reserved
+        + 4 * 9 // matrix
+        + 4 * 6 // pre_defined
+        + 4 // next_track_ID
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"mvhd", 0)?;
+        b.u32(0)?; // creation time. Not worth making the output non-deterministic.
+        b.u32(0)?; // modification time
+        b.u32(self.timescale)?;
+        b.u32(self.duration)?;
+        b.u32(0x0001_0000)?; // playback rate 1.0
+        b.u16(0x0100)?; // volume 1.0, for the sound of silence
+        b.push(&[0; 2 + 4 * 2])?;
+        for m in UNITY_MATRIX {
+            b.u32(m)?;
+        }
+        b.push(&[0; 4 * 6])?;
+        b.u32(self.next_track_id)
+    }
+}
+
+/// Track box
+#[derive(Debug, Clone)]
+pub struct TrakBox {
+    pub tkhd: TkhdBox,
+    pub tref: Option<TrefBox>,
+    pub edts: Option<EdtsBox>,
+    pub mdia: MdiaBox,
+}
+
+impl MpegBox for TrakBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + self.tkhd.len()
+            + self.tref.map_or(0, |b| b.len())
+            + self.edts.map_or(0, |b| b.len())
+            + self.mdia.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"trak")?;
+        self.tkhd.write(&mut b)?;
+        if let Some(tref) = &self.tref {
+            tref.write(&mut b)?;
+        }
+        if let Some(edts) = &self.edts {
+            edts.write(&mut b)?;
+        }
+        self.mdia.write(&mut b)
+    }
+}
+
+/// Track Reference box, with a single reference. The track equivalent of `iref`.
+#[derive(Debug, Copy, Clone)]
+pub struct TrefBox {
+    /// `auxl` from alpha to color, or `prem` from color to alpha
+    pub typ: FourCC,
+    pub track_id: u32,
+}
+
+impl MpegBox for TrefBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + BASIC_BOX_SIZE + 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"tref")?;
+        let mut r = b.basic_box(BASIC_BOX_SIZE + 4, self.typ.0)?;
+        r.u32(self.track_id)
+    }
+}
+
+/// Edit box with an Edit List that plays the whole media, once or repeatedly.
+#[derive(Debug, Copy, Clone)]
+pub struct EdtsBox {
+    /// When set, the edit list loops until the end of the track's duration (which may be indefinite)
+    pub repeat: bool,
+    /// Duration of the media, in the movie's timescale
+    pub segment_duration: u32,
+}
+
+impl MpegBox for EdtsBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+        + FULL_BOX_SIZE + 4 // elst + entry count
+        + 4 + 4 + 2 + 2 // segment_duration, media_time, media_rate
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"edts")?;
+        let mut elst = b.full_box_with_flags(FULL_BOX_SIZE + 4 + 12, *b"elst", 0, self.repeat.into())?;
+        elst.u32(1)?; // entry count
+        elst.u32(self.segment_duration)?;
+        elst.u32(0)?; // media_time: from the start
+        elst.u16(1)?; // media_rate_integer
+        elst.u16(0) // media_rate_fraction
+    }
+}
+
+/// Track Header box
+#[derive(Debug, Copy, Clone)]
+pub struct TkhdBox {
+    pub track_id: u32,
+    /// Includes repetitions from the edit list. `u32::MAX` means indefinite.
+    pub duration: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MpegBox for TkhdBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE
+        + 4 + 4 // creation/modification time
+        + 4 // track_ID
+        + 4 // reserved
+        + 4 // duration
+        + 4 * 2 // reserved
+        + 2 + 2 + 2 + 2 // layer, alternate_group, volume, reserved
+        + 4 * 9 // matrix
+        + 4 + 4 // width, height
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        // flags: track_enabled
+        let mut b = w.full_box_with_flags(self.len(), *b"tkhd", 0, 1)?;
+        b.u32(0)?; // creation time
+        b.u32(0)?; // modification time
+        b.u32(self.track_id)?;
+        b.u32(0)?;
+        b.u32(self.duration)?;
+        b.push(&[0; 4 * 2 + 2 + 2 + 2 + 2])?;
+        for m in UNITY_MATRIX {
+            b.u32(m)?;
+        }
+        // 16.16 fixed-point
+        b.u32(self.width << 16)?;
+        b.u32(self.height << 16)
+    }
+}
+
+/// Media box
+#[derive(Debug, Clone)]
+pub struct MdiaBox {
+    pub mdhd: MdhdBox,
+    pub hdlr: HdlrBox,
+    pub minf: MinfBox,
+}
+
+impl MpegBox for MdiaBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + self.mdhd.len()
+            + self.hdlr.len()
+            + self.minf.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"mdia")?;
+        self.mdhd.write(&mut b)?;
+        self.hdlr.write(&mut b)?;
+        self.minf.write(&mut b)
+    }
+}
+
+/// Media Header box
+#[derive(Debug, Copy, Clone)]
+pub struct MdhdBox {
+    pub timescale: u32,
+    pub duration: u32,
+}
+
+impl MpegBox for MdhdBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE
+        + 4 + 4 // creation/modification time
+        + 4 // timescale
+        + 4 // duration
+        + 2 // language
+        + 2 // pre_defined
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"mdhd", 0)?;
+        b.u32(0)?; // creation time
+        b.u32(0)?; // modification time
+        b.u32(self.timescale)?;
+        b.u32(self.duration)?;
+        b.u16(0x55C4)?; // "und" language, packed as 5-bit letters
+        b.u16(0)
+    }
+}
+
+/// Media Information box
+#[derive(Debug, Clone)]
+pub struct MinfBox {
+    pub stbl: StblBox,
+}
+
+impl MpegBox for MinfBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + VmhdBox.len()
+            + DinfBox.len()
+            + self.stbl.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"minf")?;
+        VmhdBox.write(&mut b)?;
+        DinfBox.write(&mut b)?;
+        self.stbl.write(&mut b)
+    }
+}
+
+/// Video Media Header box. Required, but there's nothing useful in it.
+#[derive(Debug, Copy, Clone)]
+pub struct VmhdBox;
+
+impl MpegBox for VmhdBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 2 + 2 * 3
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        // flags = 1 is mandatory for no reason
+        let mut b = w.full_box_with_flags(self.len(), *b"vmhd", 0, 1)?;
+        b.u16(0)?; // graphicsmode: copy
+        b.push(&[0; 2 * 3]) // opcolor
+    }
+}
+
+/// Data Information box, with a reference that says "the data is in this file".
+#[derive(Debug, Copy, Clone)]
+pub struct DinfBox;
+
+impl MpegBox for DinfBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+        + FULL_BOX_SIZE + 4 // dref + entry count
+        + FULL_BOX_SIZE // url
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"dinf")?;
+        let mut dref = b.full_box(FULL_BOX_SIZE + 4 + FULL_BOX_SIZE, *b"dref", 0)?;
+        dref.u32(1)?; // entry count
+        // flags = 1 means self-contained, so the URL is omitted
+        dref.full_box_with_flags(FULL_BOX_SIZE, *b"url ", 0, 1)?;
+        Ok(())
+    }
+}
+
+/// Sample Table box
+#[derive(Debug, Clone)]
+pub struct StblBox {
+    pub stsd: StsdBox,
+    pub stts: SttsBox,
+    pub stsc: StscBox,
+    pub stsz: StszBox,
+    pub stco: StcoBox,
+    pub stss: StssBox,
+}
+
+impl MpegBox for StblBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + self.stsd.len()
+            + self.stts.len()
+            + self.stsc.len()
+            + self.stsz.len()
+            + self.stco.len()
+            + if !self.stss.is_empty() { self.stss.len() } else { 0 }
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"stbl")?;
+        self.stsd.write(&mut b)?;
+        self.stts.write(&mut b)?;
+        if !self.stss.is_empty() {
+            self.stss.write(&mut b)?;
+        }
+        self.stsc.write(&mut b)?;
+        self.stsz.write(&mut b)?;
+        self.stco.write(&mut b)
+    }
+}
+
+/// Sample Description box, with just one AV1 sample entry
+#[derive(Debug, Clone)]
+pub struct StsdBox {
+    pub av01: Av01SampleEntry,
+}
+
+impl MpegBox for StsdBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + self.av01.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"stsd", 0)?;
+        b.u32(1)?; // entry count
+        self.av01.write(&mut b)
+    }
+}
+
+/// `VisualSampleEntry` for AV1, with the same redundant properties the still image gets
+#[derive(Debug, Clone)]
+pub struct Av01SampleEntry {
+    pub width: u16,
+    pub height: u16,
+    pub av1c: Av1CBox,
+    pub colr: Option<ColrBox>,
+    pub icc: Option<ColrIccBox>,
+    pub clli: Option<ClliBox>,
+    pub mdcv: Option<MdcvBox>,
+    pub cclv: Option<CclvBox>,
+    pub amve: Option<AmveBox>,
+    pub auxi: Option<AuxiBox>,
+}
+
+impl MpegBox for Av01SampleEntry {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+        + 6 + 2 // reserved, data_reference_index
+        + 2 + 2 + 4 * 3 // pre_defined, reserved
+        + 2 + 2 // width, height
+        + 4 + 4 // resolution
+        + 4 // reserved
+        + 2 // frame_count
+        + 32 // compressorname
+        + 2 + 2 // depth, pre_defined
+        + self.av1c.len()
+        + CcstBox.len()
+        + self.colr.map_or(0, |b| b.len())
+        + self.icc.as_ref().map_or(0, |b| b.len())
+        + self.clli.map_or(0, |b| b.len())
+        + self.mdcv.map_or(0, |b| b.len())
+        + self.cclv.map_or(0, |b| b.len())
+        + self.amve.map_or(0, |b| b.len())
+        + self.auxi.map_or(0, |b| b.len())
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"av01")?;
+        b.push(&[0; 6])?;
+        b.u16(1)?; // data_reference_index, 1-based index into dref
+        b.push(&[0; 2 + 2 + 4 * 3])?;
+        b.u16(self.width)?;
+        b.u16(self.height)?;
+        b.u32(0x0048_0000)?; // 72 dpi, the finest resolution of 1990s Macs
+        b.u32(0x0048_0000)?;
+        b.u32(0)?;
+        b.u16(1)?; // frame_count per sample
+        b.push(&[0; 32])?; // compressorname as a Pascal string
+        b.u16(0x0018)?; // depth: "images are in colour with no alpha", even when there's alpha
+        b.u16(0xFFFF)?; // pre_defined = -1
+        self.av1c.write(&mut b)?;
+        CcstBox.write(&mut b)?;
+        if let Some(colr) = &self.colr {
+            colr.write(&mut b)?;
+        }
+        if let Some(icc) = &self.icc {
+            icc.write(&mut b)?;
+        }
+        if let Some(clli) = &self.clli {
+            clli.write(&mut b)?;
+        }
+        if let Some(mdcv) = &self.mdcv {
+            mdcv.write(&mut b)?;
+        }
+        if let Some(cclv) = &self.cclv {
+            cclv.write(&mut b)?;
+        }
+        if let Some(amve) = &self.amve {
+            amve.write(&mut b)?;
+        }
+        if let Some(auxi) = &self.auxi {
+            auxi.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Auxiliary Type Info box. The track equivalent of `auxC`.
+#[derive(Debug, Copy, Clone)]
+pub struct AuxiBox {
+    pub urn: &'static str,
+}
+
+impl MpegBox for AuxiBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + self.urn.len() + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"auxi", 0)?;
+        b.push(self.urn.as_bytes())?;
+        b.u8(0)
+    }
+}
+
+/// Coding Constraints box, required by HEIF for image sequences
+#[derive(Debug, Copy, Clone)]
+pub struct CcstBox;
+
+impl MpegBox for CcstBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"ccst", 0)?;
+        // all_ref_pics_intra = 0, intra_pred_used = 1, max_ref_per_pic = 15 (any)
+        b.u32(0b0111_1100 << 24)
+    }
+}
+
+/// Time To Sample box. Durations of samples, run-length encoded.
+#[derive(Debug, Clone)]
+pub struct SttsBox {
+    /// (sample count, sample duration)
+    pub entries: Vec<(u32, u32)>,
+}
+
+impl SttsBox {
+    pub fn from_durations(durations: impl IntoIterator<Item = u32>) -> Self {
+        let mut entries: Vec<(u32, u32)> = Vec::new();
+        for duration in durations {
+            match entries.last_mut() {
+                Some((count, last)) if *last == duration => *count += 1,
+                _ => entries.push((1, duration)),
+            }
+        }
+        Self { entries }
+    }
+}
+
+impl MpegBox for SttsBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 8 * self.entries.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"stts", 0)?;
+        b.u32(self.entries.len() as u32)?;
+        for &(count, duration) in &self.entries {
+            b.u32(count)?;
+            b.u32(duration)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sample To Chunk box. Every sample is in its own chunk, which makes interleaving of tracks trivial.
+#[derive(Debug, Copy, Clone)]
+pub struct StscBox {
+    /// In fragmented files the sample table is empty
+    pub has_samples: bool,
+}
+
+impl MpegBox for StscBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + if self.has_samples { 4 * 3 } else { 0 }
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"stsc", 0)?;
+        if !self.has_samples {
+            return b.u32(0);
+        }
+        b.u32(1)?; // entry count
+        b.u32(1)?; // first chunk
+        b.u32(1)?; // samples per chunk
+        b.u32(1) // sample description index
+    }
+}
+
+/// Sample Size box
+#[derive(Debug, Clone)]
+pub struct StszBox {
+    pub sample_sizes: Vec<u32>,
+}
+
+impl MpegBox for StszBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 4 + 4 * self.sample_sizes.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"stsz", 0)?;
+        b.u32(0)?; // sizes vary
+        b.u32(self.sample_sizes.len() as u32)?;
+        for &size in &self.sample_sizes {
+            b.u32(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Chunk Offset box
+#[derive(Debug, Clone)]
+pub struct StcoBox {
+    /// update before writing
+    pub offsets: Vec<u32>,
+}
+
+impl MpegBox for StcoBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 4 * self.offsets.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"stco", 0)?;
+        b.u32(self.offsets.len() as u32)?;
+        for &offset in &self.offsets {
+            b.u32(offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sync Sample box. Lists keyframes (1-based). Omitted when all frames are keyframes.
+#[derive(Debug, Clone)]
+pub struct StssBox {
+    pub sample_numbers: Vec<u32>,
+}
+
+impl StssBox {
+    pub fn is_empty(&self) -> bool {
+        self.sample_numbers.is_empty()
+    }
+}
+
+impl MpegBox for StssBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 4 * self.sample_numbers.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.full_box(self.len(), *b"stss", 0)?;
+        b.u32(self.sample_numbers.len() as u32)?;
+        for &n in &self.sample_numbers {
+            b.u32(n)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MdatChunk<'data> {
+    /// Index in `moov`'s tracks
+    pub track: usize,
+    pub data: &'data [u8],
+    /// Sample of the first frame that is also the poster's item, so it points to the item's data instead of being written again
+    pub in_poster: bool,
+}
+
+/// Samples of all tracks, in file order. Poster's items (if any) are written before them.
+#[derive(Debug, Clone)]
+pub struct SequenceMdatBox<'data> {
+    pub chunks: Vec<MdatChunk<'data>>,
+}
+
+impl SequenceMdatBox<'_> {
+    /// Excludes samples that are already stored as items
+    fn unique_chunks<'a>(&'a self, iloc: Option<&'a IlocBox<'_>>) -> impl Iterator<Item = &'a [u8]> {
+        let items = iloc.into_iter().flat_map(|iloc| &iloc.items).flat_map(|i| &i.extents).map(|ex| ex.data);
+        let samples = self.chunks.iter().filter(|ch| !ch.in_poster).map(|ch| ch.data);
+        items.chain(samples)
+    }
+
+    #[inline]
+    fn len(&self, iloc: Option<&IlocBox<'_>>) -> usize {
+        BASIC_BOX_SIZE + self.unique_chunks(iloc).map(|data| data.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>, iloc: Option<&IlocBox<'_>>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(iloc), *b"mdat")?;
+        for data in self.unique_chunks(iloc) {
+            b.push(data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Movie Extends box. Announces that samples are going to be in movie fragments.
+#[derive(Debug, Clone)]
+pub struct MvexBox {
+    pub track_ids: ArrayVec<u32, 2>,
+}
+
+impl MpegBox for MvexBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + self.track_ids.len() * (FULL_BOX_SIZE + 4 * 5)
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"mvex")?;
+        for &track_id in &self.track_ids {
+            // Track Extends box. Defaults are useless, because fragments specify everything per sample.
+            let mut trex = b.full_box(FULL_BOX_SIZE + 4 * 5, *b"trex", 0)?;
+            trex.u32(track_id)?;
+            trex.u32(1)?; // default_sample_description_index
+            trex.u32(0)?; // default_sample_duration
+            trex.u32(0)?; // default_sample_size
+            trex.u32(0)?; // default_sample_flags
+        }
+        Ok(())
+    }
+}
+
+/// Movie Fragment box, followed by its own `mdat`
+#[derive(Debug, Clone)]
+pub struct MoofBox<'data> {
+    pub sequence_number: u32,
+    pub tracks: ArrayVec<TrafBox<'data>, 2>,
+}
+
+impl MoofBox<'_> {
+    /// Samples of each track follow each other in `mdat`, and `trun`'s data offsets are relative to the start of `moof`.
+    fn fix_data_offsets(&mut self) -> io::Result<()> {
+        let mut next_start = self.len() + BASIC_BOX_SIZE;
+        for traf in &mut self.tracks {
+            traf.data_offset = u32::try_from(next_start).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "fragment too large"))?;
+            next_start += traf.samples.iter().map(|s| s.data.len()).sum::<usize>();
+        }
+        Ok(())
+    }
+
+    fn mdat_len(&self) -> usize {
+        BASIC_BOX_SIZE + self.tracks.iter().flat_map(|t| t.samples).map(|s| s.data.len()).sum::<usize>()
+    }
+
+    pub fn write_with_mdat<W: Write>(&mut self, mut out: W) -> io::Result<()> {
+        self.fix_data_offsets()?;
+        if u32::try_from(self.mdat_len()).is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "fragment too large"));
+        }
+
+        let mut tmp = Vec::new();
+        tmp.try_reserve_exact(self.len())?;
+        self.write(&mut Writer::new(&mut tmp)).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        out.write_all(&tmp)?;
+        drop(tmp);
+
+        let mut out = IO(out);
+        let mut w = Writer::new(&mut out);
+        let mut b = w.basic_box(self.mdat_len(), *b"mdat")?;
+        for sample in self.tracks.iter().flat_map(|t| t.samples) {
+            b.push(sample.data)?;
+        }
+        Ok(())
+    }
+}
+
+impl MpegBox for MoofBox<'_> {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + FULL_BOX_SIZE + 4 // mfhd
+            + self.tracks.iter().map(|t| t.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"moof")?;
+        let mut mfhd = b.full_box(FULL_BOX_SIZE + 4, *b"mfhd", 0)?;
+        mfhd.u32(self.sequence_number)?;
+        drop(mfhd);
+        for t in &self.tracks {
+            t.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Track Fragment box, with `tfhd`, `tfdt` and a single `trun`
+#[derive(Debug, Clone)]
+pub struct TrafBox<'data> {
+    pub track_id: u32,
+    pub base_media_decode_time: u64,
+    /// update before writing
+    pub data_offset: u32,
+    pub samples: &'data [AnimationFrame<'data>],
+}
+
+impl TrafBox<'_> {
+    fn trun_len(&self) -> usize {
+        FULL_BOX_SIZE
+        + 4 // sample count
+        + 4 // data offset
+        + self.samples.len() * (4 + 4 + 4) // duration, size, flags
+    }
+}
+
+impl MpegBox for TrafBox<'_> {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + FULL_BOX_SIZE + 4 // tfhd
+            + FULL_BOX_SIZE + 8 // tfdt
+            + self.trun_len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.basic_box(self.len(), *b"traf")?;
+
+        // flags: default-base-is-moof
+        let mut tfhd = b.full_box_with_flags(FULL_BOX_SIZE + 4, *b"tfhd", 0, 0x02_0000)?;
+        tfhd.u32(self.track_id)?;
+        drop(tfhd);
+
+        let mut tfdt = b.full_box(FULL_BOX_SIZE + 8, *b"tfdt", 1)?;
+        tfdt.u64(self.base_media_decode_time)?;
+        drop(tfdt);
+
+        // flags: data-offset, sample-duration, sample-size, sample-flags present
+        let mut trun = b.full_box_with_flags(self.trun_len(), *b"trun", 0, 0x00_0701)?;
+        trun.u32(self.samples.len() as u32)?;
+        trun.u32(self.data_offset)?;
+        for s in self.samples {
+            trun.u32(s.duration)?;
+            trun.u32(s.data.len() as u32)?;
+            // sample_depends_on = 2 (keyframe), or sample_depends_on = 1 + sample_is_non_sync_sample
+            trun.u32(if s.is_keyframe { 0x0200_0000 } else { 0x0101_0000 })?;
+        }
+        Ok(())
+    }
+}